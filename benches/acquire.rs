@@ -0,0 +1,30 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use TLBoS::semaphore::Semaphore;
+
+fn try_acquire_uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("try_acquire");
+
+    group.bench_function("available", |b| {
+        let sem = Semaphore::new(isize::MAX);
+        b.iter(|| black_box(sem.try_acquire()));
+    });
+
+    group.bench_function("exhausted", |b| {
+        let sem = Semaphore::new(0);
+        b.iter(|| black_box(sem.try_acquire()));
+    });
+
+    group.bench_function("with_release", |b| {
+        let sem = Semaphore::new(1);
+        b.iter(|| {
+            black_box(sem.try_acquire());
+            sem.release();
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, try_acquire_uncontended);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use TLBoS::semaphore::Semaphore;
+
+/// Touches every word of the shared data, as a holder of the resource would, so that the cost of
+/// moving its cache lines between CPUs shows up in the handoffs.
+fn touch(data: &[AtomicU64]) {
+    for word in data {
+        word.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn fair_wakeups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fair_wakeup");
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 500;
+    const WORDS: usize = 512;
+
+    for &local in [false, true].iter() {
+        let name = if local {
+            "locality_aware"
+        } else {
+            "strict_fifo"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let sem = Arc::new(
+                    Semaphore::builder(2)
+                        .fair(true)
+                        .prefer_local_waiters(local)
+                        .build(),
+                );
+                let data: Arc<Vec<AtomicU64>> =
+                    Arc::new((0..WORDS).map(|_| AtomicU64::new(0)).collect());
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|_| {
+                        let (sem, data) = (sem.clone(), data.clone());
+                        thread::spawn(move || {
+                            for _ in 0..ROUNDS {
+                                let _g = sem.access();
+                                touch(&data);
+                            }
+                        })
+                    })
+                    .collect();
+                for h in handles {
+                    h.join().unwrap();
+                }
+                black_box(&data);
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fair_wakeups);
+criterion_main!(benches);
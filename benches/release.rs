@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use TLBoS::semaphore::Semaphore;
+
+fn release_loop_vs_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("release");
+
+    for &k in [1, 16, 256, 4096].iter() {
+        group.bench_with_input(BenchmarkId::new("loop", k), &k, |b, &k| {
+            let sem = Semaphore::new(0);
+            b.iter(|| {
+                for _ in 0..k {
+                    sem.release();
+                }
+            });
+            black_box(&sem);
+        });
+
+        group.bench_with_input(BenchmarkId::new("release_n", k), &k, |b, &k| {
+            let sem = Semaphore::new(0);
+            b.iter(|| sem.release_n(black_box(k)));
+            black_box(&sem);
+        });
+    }
+
+    group.finish();
+}
+
+/// Hands a resource back and forth between two threads, which wakes a blocked thread on every
+/// release.
+fn ping_pong(ping: &Semaphore, pong: &Semaphore, rounds: usize) {
+    for _ in 0..rounds {
+        ping.release();
+        pong.acquire();
+    }
+}
+
+fn notify_ordering(c: &mut Criterion) {
+    let mut group = c.benchmark_group("notify");
+    const ROUNDS: usize = 1000;
+
+    for &after_unlock in [false, true].iter() {
+        let name = if after_unlock {
+            "after_unlock"
+        } else {
+            "before_unlock"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let build = || {
+                    Arc::new(
+                        Semaphore::builder(0)
+                            .notify_after_unlock(after_unlock)
+                            .build(),
+                    )
+                };
+                let (ping, pong) = (build(), build());
+                let (ping2, pong2) = (ping.clone(), pong.clone());
+                let t = thread::spawn(move || ping_pong(&ping2, &pong2, ROUNDS));
+                for _ in 0..ROUNDS {
+                    ping.acquire();
+                    pong.release();
+                }
+                t.join().unwrap();
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn coalescing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("coalesce");
+    const THREADS: usize = 8;
+    const RELEASES: usize = 10_000;
+
+    for &coalesce in [false, true].iter() {
+        let name = if coalesce { "coalesced" } else { "direct" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let sem = Arc::new(Semaphore::builder(0).coalesce_releases(coalesce).build());
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|_| {
+                        let sem = sem.clone();
+                        thread::spawn(move || {
+                            for _ in 0..RELEASES {
+                                sem.release();
+                            }
+                        })
+                    })
+                    .collect();
+                for h in handles {
+                    h.join().unwrap();
+                }
+                black_box(&sem);
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn fast_release(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_producer");
+    const RELEASES: usize = 10_000;
+
+    for &fast in [false, true].iter() {
+        let name = if fast { "fast_release" } else { "release" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let sem = Arc::new(Semaphore::new(0));
+                let consumers: Vec<_> = (0..4)
+                    .map(|_| {
+                        let sem = sem.clone();
+                        thread::spawn(move || {
+                            for _ in 0..RELEASES / 4 {
+                                sem.acquire();
+                            }
+                        })
+                    })
+                    .collect();
+                for _ in 0..RELEASES {
+                    if fast {
+                        sem.fast_release();
+                    } else {
+                        sem.release();
+                    }
+                }
+                for h in consumers {
+                    h.join().unwrap();
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn batch_wakeups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch_wakeup");
+    const WAITERS: usize = 16;
+    const ACQUIRES: usize = 500;
+    const BATCH: isize = 4;
+
+    for &exact in [false, true].iter() {
+        let name = if exact { "exact" } else { "notify_all" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let sem = Arc::new(Semaphore::builder(0).exact_wakeups(exact).build());
+                let waiters: Vec<_> = (0..WAITERS)
+                    .map(|_| {
+                        let sem = sem.clone();
+                        thread::spawn(move || {
+                            for _ in 0..ACQUIRES {
+                                sem.acquire();
+                            }
+                        })
+                    })
+                    .collect();
+                for _ in 0..WAITERS * ACQUIRES / BATCH as usize {
+                    sem.release_n(BATCH);
+                }
+                for h in waiters {
+                    h.join().unwrap();
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    release_loop_vs_batch,
+    notify_ordering,
+    coalescing,
+    fast_release,
+    batch_wakeups
+);
+criterion_main!(benches);
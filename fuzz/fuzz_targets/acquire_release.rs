@@ -0,0 +1,50 @@
+#![no_main]
+
+use std::sync::Arc;
+use std::thread;
+
+use libfuzzer_sys::fuzz_target;
+use TLBoS::semaphore::Semaphore;
+
+const THREADS: usize = 4;
+
+// The first byte picks the initial count, and the rest are split between the threads as a script
+// of operations: odd bytes try to acquire a resource, even bytes release one if the thread holds
+// any. Once every thread has released what it still holds, the count must be back where it
+// started.
+fuzz_target!(|data: &[u8]| {
+    let (&initial, script) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let initial = isize::from(initial % 8);
+    let sem = Arc::new(Semaphore::with_max(initial, initial));
+
+    let chunk = script.len() / THREADS + 1;
+    let handles: Vec<_> = script
+        .chunks(chunk)
+        .map(|ops| {
+            let (sem, ops) = (sem.clone(), ops.to_vec());
+            thread::spawn(move || {
+                let mut held = 0;
+                for op in ops {
+                    if op % 2 == 1 {
+                        if sem.try_acquire() {
+                            held += 1;
+                        }
+                    } else if held > 0 {
+                        sem.release();
+                        held -= 1;
+                    }
+                    assert!(sem.available_permits() >= 0);
+                }
+                sem.release_n(held);
+            })
+        })
+        .collect();
+
+    for h in handles {
+        h.join().unwrap();
+    }
+    assert_eq!(sem.available_permits(), initial);
+});
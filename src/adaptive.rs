@@ -0,0 +1,259 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::observer::SemaphoreObserver;
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// The algorithm an `AdaptiveSemaphore` uses to pick its next limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Additive increase, multiplicative decrease: grows the limit by `increase` when waits are
+    /// too long, and multiplies it by `decrease` when it is underused.
+    Aimd { increase: isize, decrease: f64 },
+    /// Scales the limit by how far the average wait is from the target, growing it by at most
+    /// `max_growth` times per window. Underuse multiplies the limit by `decrease`.
+    Gradient { max_growth: f64, decrease: f64 },
+}
+
+/// The parameters of an `AdaptiveSemaphore`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveConfig {
+    /// The average acquisition wait the semaphore steers towards.
+    pub target_wait: Duration,
+    /// The limit is never lowered below this.
+    pub min_limit: isize,
+    /// The limit is never raised above this.
+    pub max_limit: isize,
+    /// The number of acquisitions between adjustments.
+    pub window: usize,
+    /// The limit is lowered when the peak fraction of it in use during a window is below this.
+    pub low_utilization: f64,
+    /// How the next limit is chosen.
+    pub algorithm: Algorithm,
+}
+
+/// A semaphore which adjusts its limit to keep acquisition waits near a target.
+///
+/// After every window of acquisitions, the average wait reported by the semaphore's observer is
+/// compared against the target. Waits above it mean callers are queueing for resources, so the
+/// limit is raised with `Semaphore::add_permits`; a window in which most of the limit went unused
+/// lowers it with `Semaphore::forget`.
+pub struct AdaptiveSemaphore {
+    /// The semaphore whose resources are handed out.
+    sem: Semaphore,
+    /// The statistics gathered for the current window.
+    window: Arc<Mutex<Window>>,
+    /// The controller's parameters.
+    config: AdaptiveConfig,
+    /// The current limit, guarded separately from the window so that adjustments are serialized.
+    limit: Mutex<isize>,
+}
+
+/// The statistics gathered over a window of acquisitions.
+#[derive(Default)]
+struct Window {
+    /// The number of acquisitions in the window.
+    acquisitions: usize,
+    /// The total time those acquisitions spent waiting.
+    total_wait: Duration,
+    /// The most resources in use at once during the window.
+    peak_in_use: isize,
+}
+
+/// Feeds acquisition waits from the semaphore into the current window.
+struct WaitRecorder(Arc<Mutex<Window>>);
+
+impl SemaphoreObserver for WaitRecorder {
+    fn on_acquire(&self, _n: isize, waited: Duration) {
+        let mut window = self.0.lock().unwrap();
+        window.acquisitions += 1;
+        window.total_wait += waited;
+    }
+}
+
+impl Algorithm {
+    /// Returns the next limit given the current one and the window's average wait, before
+    /// clamping.
+    fn grow(&self, limit: isize, average_wait: Duration, target_wait: Duration) -> isize {
+        match *self {
+            Algorithm::Aimd { increase, .. } => limit + increase,
+            Algorithm::Gradient { max_growth, .. } => {
+                let ratio = average_wait.as_secs_f64() / target_wait.as_secs_f64().max(1e-9);
+                let next = (limit as f64 * ratio.min(max_growth)).ceil() as isize;
+                next.max(limit + 1)
+            }
+        }
+    }
+
+    fn shrink(&self, limit: isize) -> isize {
+        let decrease = match *self {
+            Algorithm::Aimd { decrease, .. } | Algorithm::Gradient { decrease, .. } => decrease,
+        };
+        (limit as f64 * decrease).floor() as isize
+    }
+}
+
+impl AdaptiveSemaphore {
+    /// Creates an adaptive semaphore starting with `initial` resources.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `initial` is outside the configured limits, or if the configured window is empty.
+    pub fn new(initial: isize, config: AdaptiveConfig) -> Self {
+        assert!(
+            config.min_limit <= initial && initial <= config.max_limit,
+            "initial limit is outside the configured limits"
+        );
+        assert!(config.window > 0, "adaptive window must not be empty");
+        let window = Arc::new(Mutex::new(Window::default()));
+        AdaptiveSemaphore {
+            sem: Semaphore::with_observer(initial, Arc::new(WaitRecorder(window.clone()))),
+            window,
+            config,
+            limit: Mutex::new(initial),
+        }
+    }
+
+    /// Returns the current limit on concurrent acquisitions.
+    pub fn limit(&self) -> isize {
+        *self.limit.lock().unwrap()
+    }
+
+    /// Acquires a resource, blocking until one is available, and adjusts the limit if this
+    /// completes a window.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        let guard = self.sem.access();
+        self.record();
+        guard
+    }
+
+    /// Records the resources in use after an acquisition, and adjusts the limit at the end of a
+    /// window.
+    fn record(&self) {
+        let mut limit = self.limit.lock().unwrap();
+        let finished = {
+            let mut window = self.window.lock().unwrap();
+            let in_use = *limit - self.sem.available_permits();
+            window.peak_in_use = window.peak_in_use.max(in_use);
+            if window.acquisitions < self.config.window {
+                return;
+            }
+            std::mem::take(&mut *window)
+        };
+
+        let average_wait = finished.total_wait / finished.acquisitions as u32;
+        let utilization = finished.peak_in_use as f64 / *limit as f64;
+        let algorithm = self.config.algorithm;
+
+        let next = if average_wait > self.config.target_wait {
+            algorithm.grow(*limit, average_wait, self.config.target_wait)
+        } else if utilization < self.config.low_utilization {
+            algorithm.shrink(*limit)
+        } else {
+            *limit
+        };
+        let next = next.max(self.config.min_limit).min(self.config.max_limit);
+
+        if next > *limit {
+            self.sem.add_permits(next - *limit);
+            *limit = next;
+        } else if next < *limit {
+            *limit -= self.sem.forget(*limit - next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn config(algorithm: Algorithm) -> AdaptiveConfig {
+        AdaptiveConfig {
+            target_wait: Duration::from_millis(1),
+            min_limit: 1,
+            max_limit: 8,
+            window: 4,
+            low_utilization: 0.5,
+            algorithm,
+        }
+    }
+
+    fn contend(sem: &Arc<AdaptiveSemaphore>) {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..4 {
+                        let _g = sem.access();
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_adaptive_grows_under_contention() {
+        let aimd = Algorithm::Aimd {
+            increase: 1,
+            decrease: 0.5,
+        };
+        let sem = Arc::new(AdaptiveSemaphore::new(1, config(aimd)));
+        contend(&sem);
+        assert!(sem.limit() > 1);
+        assert!(sem.limit() <= 8);
+    }
+
+    #[test]
+    fn test_adaptive_gradient_grows_under_contention() {
+        let gradient = Algorithm::Gradient {
+            max_growth: 2.0,
+            decrease: 0.5,
+        };
+        let sem = Arc::new(AdaptiveSemaphore::new(1, config(gradient)));
+        contend(&sem);
+        assert!(sem.limit() > 1);
+    }
+
+    #[test]
+    fn test_adaptive_shrinks_when_underused() {
+        let aimd = Algorithm::Aimd {
+            increase: 1,
+            decrease: 0.5,
+        };
+        let sem = AdaptiveSemaphore::new(8, config(aimd));
+
+        // One resource at a time is a utilization of 1/8, well below the threshold.
+        for _ in 0..4 {
+            drop(sem.access());
+        }
+        assert_eq!(sem.limit(), 4);
+
+        // Shrinking stops once half of the limit is in use.
+        for _ in 0..16 {
+            drop(sem.access());
+        }
+        assert_eq!(sem.limit(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "adaptive window must not be empty")]
+    fn test_adaptive_empty_window() {
+        let aimd = Algorithm::Aimd {
+            increase: 1,
+            decrease: 0.5,
+        };
+        AdaptiveSemaphore::new(
+            1,
+            AdaptiveConfig {
+                window: 0,
+                ..config(aimd)
+            },
+        );
+    }
+}
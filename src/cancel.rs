@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+
+/// A cancellation flag shared by a tree of nested operations, for aborting acquisitions made with
+/// `Semaphore::acquire_in_scope`.
+///
+/// A scope is cancelled either directly or through any of its ancestors, so cancelling a parent
+/// scope aborts every acquisition in its child scopes too. Clones refer to the same scope.
+#[derive(Clone, Default)]
+pub struct CancelScope {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Set once the scope itself has been cancelled.
+    cancelled: AtomicBool,
+    /// The scope this one was created in, if any.
+    parent: Option<Arc<Inner>>,
+    /// The threads waiting in this scope or any of its descendants, which cancelling it wakes.
+    waiters: Mutex<Vec<Thread>>,
+}
+
+/// Keeps the current thread registered with a scope and its ancestors while it waits, so that
+/// cancelling any of them unparks it.
+pub(crate) struct Registration<'a> {
+    scope: &'a CancelScope,
+}
+
+impl CancelScope {
+    /// Creates a scope with no parent.
+    pub fn new() -> Self {
+        CancelScope::default()
+    }
+
+    /// Creates a scope nested in this one, which is cancelled whenever this one is.
+    pub fn child(&self) -> CancelScope {
+        CancelScope {
+            inner: Arc::new(Inner {
+                parent: Some(self.inner.clone()),
+                ..Inner::default()
+            }),
+        }
+    }
+
+    /// Cancels the scope and all of its descendants, waking any acquisition waiting in them.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for waiter in self.inner.waiters.lock().unwrap().iter() {
+            waiter.unpark();
+        }
+    }
+
+    /// Returns whether the scope or any of its ancestors has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.ancestry()
+            .any(|scope| scope.cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Registers the current thread to be unparked if the scope is cancelled, until the returned
+    /// registration is dropped.
+    ///
+    /// A cancellation racing with the registration either sees the thread registered or is seen
+    /// by `is_cancelled` afterwards.
+    pub(crate) fn register(&self) -> Registration<'_> {
+        for scope in self.ancestry() {
+            scope.waiters.lock().unwrap().push(thread::current());
+        }
+        Registration { scope: self }
+    }
+
+    /// Returns the scope followed by its ancestors, innermost first.
+    fn ancestry(&self) -> impl Iterator<Item = &Inner> {
+        let mut next = Some(&*self.inner);
+        std::iter::from_fn(move || {
+            let scope = next?;
+            next = scope.parent.as_deref();
+            Some(scope)
+        })
+    }
+}
+
+impl<'a> Drop for Registration<'a> {
+    fn drop(&mut self) {
+        let id = thread::current().id();
+        for scope in self.scope.ancestry() {
+            let mut waiters = scope.waiters.lock().unwrap();
+            if let Some(i) = waiters.iter().position(|waiter| waiter.id() == id) {
+                waiters.remove(i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_scope_ancestry() {
+        let parent = CancelScope::new();
+        let child = parent.child();
+        let sibling = parent.child();
+        assert!(!child.is_cancelled());
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!sibling.is_cancelled());
+        assert!(!parent.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(child.child().is_cancelled());
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::Deref;
+
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// A value whose concurrent access is limited by a semaphore.
+///
+/// Up to `n` threads may hold a shared reference to the value at once; any further accesses block
+/// until one of the existing holders drops its guard.
+pub struct SemaphoreCell<T> {
+    /// The semaphore limiting access to the value.
+    sem: Semaphore,
+    /// The protected value.
+    value: T,
+}
+
+/// An RAII guard giving access to the value of a `SemaphoreCell`, which releases its resource
+/// when dropped.
+pub struct SemaphoreCellGuard<'a, T> {
+    /// The guard holding the resource acquired from the cell's semaphore.
+    _guard: SemaphoreGuard<'a>,
+    /// The protected value.
+    value: &'a T,
+}
+
+/// An RAII guard giving access to part of the value of a `SemaphoreCell`, created by
+/// `SemaphoreCellGuard::map`. It releases its resource when dropped.
+pub struct MappedGuard<'a, U> {
+    /// The guard holding the resource acquired from the cell's semaphore.
+    _guard: SemaphoreGuard<'a>,
+    /// The projected part of the protected value.
+    value: &'a U,
+}
+
+impl<T> SemaphoreCell<T> {
+    /// Wraps `value` in a cell allowing up to `n` concurrent accesses.
+    pub fn new(n: isize, value: T) -> Self {
+        SemaphoreCell {
+            sem: Semaphore::new(n),
+            value,
+        }
+    }
+
+    /// Acquires a resource of the cell's semaphore, blocking until one is available, and returns
+    /// a guard giving access to the value.
+    pub fn access(&self) -> SemaphoreCellGuard<'_, T> {
+        SemaphoreCellGuard {
+            _guard: self.sem.access(),
+            value: &self.value,
+        }
+    }
+
+    /// Attempts to acquire a resource of the cell's semaphore without waiting, returning a guard
+    /// giving access to the value if one was available.
+    pub fn try_access(&self) -> Option<SemaphoreCellGuard<'_, T>> {
+        self.sem.try_access().map(|guard| SemaphoreCellGuard {
+            _guard: guard,
+            value: &self.value,
+        })
+    }
+
+    /// Consumes the cell, returning the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<'a, T> SemaphoreCellGuard<'a, T> {
+    /// Makes a guard for a part of the protected value, such as one of its fields.
+    ///
+    /// The resource is transferred to the returned guard and released when it is dropped.
+    pub fn map<U, F>(self, f: F) -> MappedGuard<'a, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        MappedGuard {
+            _guard: self._guard,
+            value: f(self.value),
+        }
+    }
+}
+
+impl<'a, U> MappedGuard<'a, U> {
+    /// Makes a guard for a further part of the projected value.
+    pub fn map<V, F>(self, f: F) -> MappedGuard<'a, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        MappedGuard {
+            _guard: self._guard,
+            value: f(self.value),
+        }
+    }
+}
+
+impl<'a, T> Deref for SemaphoreCellGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, U> Deref for MappedGuard<'a, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Config {
+        name: String,
+        retries: u32,
+    }
+
+    #[test]
+    fn test_cell_access() {
+        let cell = SemaphoreCell::new(2, 5);
+        let a = cell.access();
+        let b = cell.access();
+        assert_eq!(*a + *b, 10);
+        assert!(cell.try_access().is_none());
+        drop(a);
+        assert!(cell.try_access().is_some());
+    }
+
+    #[test]
+    fn test_cell_map() {
+        let cell = SemaphoreCell::new(
+            1,
+            Config {
+                name: "db".to_string(),
+                retries: 3,
+            },
+        );
+
+        let name = cell.access().map(|c| &c.name);
+        assert_eq!(&*name, "db");
+        assert!(cell.try_access().is_none());
+        drop(name);
+
+        let retries = cell.access().map(|c| &c.retries);
+        assert_eq!(*retries, 3);
+        drop(retries);
+
+        assert!(cell.try_access().is_some());
+    }
+}
@@ -0,0 +1,296 @@
+//! Per-thread tracking of the resources each thread holds, for the debug checks on what threads
+//! hold: lock order checking with the `lock-order` feature, and nested hold detection with the
+//! `nested-holds` feature.
+//!
+//! Every semaphore is given a key, and each thread records the key of every semaphore it holds
+//! resources from, together with how many it holds. Resources are recorded when a thread acquires
+//! them and forgotten when it releases them, so resources which are taken out of a semaphore
+//! without anyone holding them, as by `Semaphore::forget`, are never recorded.
+//!
+//! With lock order checking, every semaphore also has a place in a global order, its creation
+//! order unless it shares another's, and a blocking acquisition panics if the thread already
+//! holds a semaphore placed after the one it is acquiring. Acquiring semaphores in a consistent
+//! global order is the classic way to rule out deadlocks between them, so acquiring out of order
+//! is reported even if it happens not to deadlock this time. With nested hold detection, a thread
+//! acquiring from a semaphore it already holds a resource from is recorded as a nested hold,
+//! without interfering with the acquisition. Nested holds are legal, but often point to code
+//! which deadlocks once the semaphore runs short.
+//!
+//! Holds are tracked per thread, so a thread releasing resources it isn't recorded as holding
+//! can't say whose records they came off. Such a semaphore is taken to be used for signalling
+//! between threads rather than for locking, as when one thread waits for another to release it or
+//! an `OwnedSemaphoreGuard` is sent across threads, and it is no longer tracked from then on: the
+//! records every thread has of it are ignored and dropped, so they can't set off a false lock
+//! order violation or nested hold.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The keys of the semaphores the current thread holds resources from, with the number of
+    /// resources it holds from each.
+    static HELD: RefCell<Vec<(HoldKey, isize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Identifies a semaphore to the hold tracker.
+#[derive(Clone)]
+pub(crate) struct HoldKey {
+    /// Unique to each semaphore.
+    id: u64,
+    /// The semaphore's place in the global lock order.
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    order: u64,
+    /// Whether a thread has released resources of the semaphore which it wasn't recorded as
+    /// holding, after which the semaphore is no longer tracked. It is shared with the records
+    /// other threads keep, so that they can see it too.
+    signalling: Arc<AtomicBool>,
+}
+
+impl HoldKey {
+    /// Returns the key for a newly created semaphore, which is placed last in the lock order.
+    pub(crate) fn new() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HoldKey {
+            id,
+            #[cfg(all(feature = "lock-order", debug_assertions))]
+            order: id,
+            signalling: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the semaphore's place in the global lock order.
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    pub(crate) fn order(&self) -> u64 {
+        self.order
+    }
+
+    /// Gives the semaphore the same place in the lock order as the one with key `other`.
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    pub(crate) fn share_order(&mut self, other: &HoldKey) {
+        self.order = other.order;
+    }
+
+    /// Returns whether the semaphore is no longer tracked.
+    fn is_signalling(&self) -> bool {
+        self.signalling.load(Ordering::Relaxed)
+    }
+}
+
+/// Checks that the current thread may block on the semaphore with the given key.
+///
+/// # Panics
+///
+/// Panics if the thread holds a resource from a semaphore placed after it in the lock order.
+#[cfg(all(feature = "lock-order", debug_assertions))]
+pub(crate) fn check_order(key: &HoldKey) {
+    let violation = HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        held.retain(|(h, _)| !h.is_signalling());
+        held.iter()
+            .map(|(h, _)| h.order)
+            .find(|&order| order > key.order)
+    });
+    if let Some(held) = violation {
+        panic!(
+            "lock order violation: acquiring semaphore #{} while holding semaphore #{}",
+            key.order, held
+        );
+    }
+}
+
+/// Records that the current thread acquired `n` resources from the semaphore with the given key,
+/// and returns whether it already held one from it.
+pub(crate) fn acquired(key: &HoldKey, n: isize) -> bool {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if key.is_signalling() {
+            held.retain(|(h, _)| h.id != key.id);
+            return false;
+        }
+        match held.iter_mut().find(|(h, _)| h.id == key.id) {
+            Some((_, count)) => {
+                *count = count.saturating_add(n);
+                true
+            }
+            None => {
+                held.push((key.clone(), n));
+                false
+            }
+        }
+    })
+}
+
+/// Records that the current thread released `n` resources to the semaphore with the given key,
+/// giving up on tracking the semaphore if it doesn't hold that many.
+pub(crate) fn released(key: &HoldKey, n: isize) {
+    // Unwinding may release resources while thread locals are being torn down.
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        let i = held.iter().position(|(h, _)| h.id == key.id);
+        let count = i.map_or(0, |i| held[i].1);
+        if count < n {
+            key.signalling.store(true, Ordering::Relaxed);
+        }
+        if let Some(i) = i {
+            if count <= n {
+                held.swap_remove(i);
+            } else {
+                held[i].1 -= n;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::semaphore::Semaphore;
+
+    /// Returns the number of semaphores the current thread is recorded as holding resources from.
+    fn held_semaphores() -> usize {
+        HELD.with(|held| held.borrow().len())
+    }
+
+    #[test]
+    fn test_holds_counted() {
+        let sem = Semaphore::new(isize::MAX);
+        let all = sem.try_acquire_all().unwrap();
+        assert_eq!(held_semaphores(), 1);
+        drop(all);
+        assert_eq!(held_semaphores(), 0);
+    }
+
+    #[test]
+    fn test_holds_released_elsewhere() {
+        // A consumer which never releases what it takes is charged one record, however much it
+        // takes.
+        let sem = Arc::new(Semaphore::new(0));
+        let producer = {
+            let sem = sem.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    sem.release();
+                }
+            })
+        };
+        for _ in 0..1000 {
+            sem.acquire();
+        }
+        producer.join().unwrap();
+        assert!(held_semaphores() <= 1);
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    fn test_lock_order_consistent() {
+        let (a, b) = (Semaphore::new(1), Semaphore::new(1));
+        for _ in 0..2 {
+            let _a = a.access();
+            let _b = b.access();
+        }
+
+        // Holding only the later semaphore is fine once the earlier one is released.
+        let _b = b.access();
+        assert!(b.try_access().is_none());
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn test_lock_order_violation() {
+        let (a, b) = (Semaphore::new(1), Semaphore::new(1));
+        let _b = b.access();
+        let _a = a.access();
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    fn test_lock_order_released() {
+        let (a, b) = (Semaphore::new(1), Semaphore::new(1));
+        drop(b.access());
+        let _a = a.access();
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    fn test_lock_order_forget() {
+        // Forgotten and shrunk resources are held by no one, so they don't count against the
+        // thread which removed them.
+        let (a, b) = (Semaphore::new(1), Semaphore::new(2));
+        b.forget(1);
+        b.shrink_to(0);
+        let _a = a.access();
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    fn test_lock_order_signalled() {
+        // Waiting for a signal takes a resource which is never released by this thread, so it
+        // mustn't count against acquiring an earlier semaphore afterwards.
+        let (a, signal) = (Semaphore::new(1), Arc::new(Semaphore::new(0)));
+        let signaller = signal.clone();
+        let t = thread::spawn(move || signaller.release());
+        signal.acquire();
+        t.join().unwrap();
+        let _a = a.access();
+        assert_eq!(held_semaphores(), 1);
+    }
+
+    #[cfg(all(feature = "lock-order", debug_assertions))]
+    #[test]
+    fn test_lock_order_signalled_after_acquire() {
+        let (a, signal) = (Semaphore::new(1), Arc::new(Semaphore::new(1)));
+        signal.acquire();
+        let signaller = signal.clone();
+        thread::spawn(move || signaller.release()).join().unwrap();
+        let _a = a.access();
+    }
+
+    #[cfg(feature = "nested-holds")]
+    #[test]
+    fn test_nested_hold_signalled() {
+        let sem = Arc::new(Semaphore::new(1));
+        sem.acquire();
+        let signaller = sem.clone();
+        thread::spawn(move || signaller.release()).join().unwrap();
+        let _a = sem.access();
+        assert!(!sem.nested_hold_detected());
+    }
+
+    #[cfg(feature = "nested-holds")]
+    #[test]
+    fn test_nested_hold_detected() {
+        let sem = Semaphore::new(2);
+        let _a = sem.access();
+        assert!(!sem.nested_hold_detected());
+        let _b = sem.access();
+        assert!(sem.nested_hold_detected());
+    }
+
+    #[cfg(feature = "nested-holds")]
+    #[test]
+    fn test_nested_hold_sequential() {
+        let sem = Semaphore::new(2);
+        drop(sem.access());
+        drop(sem.access());
+        // Taking several resources in one acquisition isn't nesting either.
+        drop(sem.acquire_checked_n(2).unwrap());
+        assert!(!sem.nested_hold_detected());
+    }
+
+    #[cfg(feature = "nested-holds")]
+    #[test]
+    fn test_nested_hold_forget() {
+        let sem = Semaphore::new(2);
+        sem.forget(1);
+        let _a = sem.access();
+        assert!(!sem.nested_hold_detected());
+    }
+}
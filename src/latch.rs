@@ -0,0 +1,164 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::semaphore::Semaphore;
+
+/// A one-shot gate which blocks threads until it is opened, and then lets every thread through
+/// for good.
+///
+/// The latch is a turnstile: a semaphore starting at zero which each waiter acquires and
+/// immediately releases again. Opening the latch releases the first resource, after which every
+/// waiter passes straight through, letting the next one through as it goes.
+pub struct Latch {
+    /// The turnstile, which is unlocked once the latch is opened.
+    turnstile: Semaphore,
+    /// Whether the latch has been opened, so that opening it again does nothing.
+    opened: AtomicBool,
+}
+
+/// A gate which blocks threads until it has been counted down a given number of times, after
+/// which it lets every thread through for good.
+///
+/// This is the dual of a barrier: the threads counting down never wait, and the threads waiting
+/// never count down. The gate itself is a `Latch`, opened by the final count down.
+pub struct CountdownLatch {
+    /// The number of count downs still needed.
+    remaining: AtomicUsize,
+    /// Opened once `remaining` reaches zero.
+    latch: Latch,
+}
+
+impl Latch {
+    /// Creates a closed latch.
+    pub fn new() -> Self {
+        Latch {
+            turnstile: Semaphore::new(0),
+            opened: AtomicBool::new(false),
+        }
+    }
+
+    /// Opens the latch, permanently unblocking all current and future callers of `wait`.
+    ///
+    /// Opening a latch which is already open has no effect.
+    pub fn open(&self) {
+        if !self.opened.swap(true, Ordering::AcqRel) {
+            self.turnstile.release();
+        }
+    }
+
+    /// Returns whether the latch has been opened.
+    pub fn is_open(&self) -> bool {
+        self.opened.load(Ordering::Acquire)
+    }
+
+    /// Blocks the current thread until the latch is opened, returning immediately if it already
+    /// is.
+    pub fn wait(&self) {
+        if self.is_open() {
+            return;
+        }
+        self.turnstile.acquire();
+        self.turnstile.release();
+    }
+}
+
+impl Default for Latch {
+    fn default() -> Self {
+        Latch::new()
+    }
+}
+
+impl CountdownLatch {
+    /// Creates a latch which opens after `n` count downs, or which is already open if `n` is
+    /// zero.
+    pub fn new(n: usize) -> Self {
+        let latch = Latch::new();
+        if n == 0 {
+            latch.open();
+        }
+        CountdownLatch {
+            remaining: AtomicUsize::new(n),
+            latch,
+        }
+    }
+
+    /// Counts the latch down, opening it if this was the last count down needed.
+    ///
+    /// Counting down a latch which is already open has no effect.
+    pub fn count_down(&self) {
+        let prev = self
+            .remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if prev == Ok(1) {
+            self.latch.open();
+        }
+    }
+
+    /// Returns the number of count downs still needed to open the latch.
+    pub fn count(&self) -> usize {
+        self.remaining.load(Ordering::Acquire)
+    }
+
+    /// Blocks the current thread until the latch has been counted down to zero, returning
+    /// immediately if it already has.
+    pub fn await_zero(&self) {
+        self.latch.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_latch_open() {
+        let latch = Arc::new(Latch::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let latch = latch.clone();
+                thread::spawn(move || latch.wait())
+            })
+            .collect();
+
+        latch.open();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        // Late waiters, and opening again, don't block.
+        latch.open();
+        latch.wait();
+        assert!(latch.is_open());
+    }
+
+    #[test]
+    fn test_countdown_latch() {
+        let latch = Arc::new(CountdownLatch::new(3));
+        let (tx, rx) = channel();
+        let waiter = latch.clone();
+        let t = thread::spawn(move || {
+            waiter.await_zero();
+            tx.send(()).unwrap();
+        });
+
+        latch.count_down();
+        latch.count_down();
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(latch.count(), 1);
+
+        latch.count_down();
+        rx.recv().unwrap();
+        t.join().unwrap();
+
+        // Once open it stays open, and further count downs change nothing.
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        latch.await_zero();
+        CountdownLatch::new(0).await_zero();
+    }
+}
@@ -1,2 +1,31 @@
+#![allow(non_snake_case)]
+
+#[macro_use]
+mod macros;
+
+pub mod adaptive;
+pub mod cancel;
+pub mod cell;
+pub mod latch;
+pub mod mutex;
+pub mod observer;
+pub mod partition;
+pub mod pool;
+pub mod probe;
+pub mod queue;
 pub mod semaphore;
+pub mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod watchdog;
 // pub mod dining_philosophers;
+
+#[cfg(any(
+    all(feature = "lock-order", debug_assertions),
+    feature = "nested-holds"
+))]
+mod holds;
+#[cfg(feature = "numa")]
+mod locality;
+mod sync;
+mod wait_queue;
@@ -0,0 +1,99 @@
+//! Where the current thread is running, for the locality-aware wakeups of fair semaphores.
+//!
+//! A thread's location is the CPU it last ran on, together with the NUMA node that CPU belongs
+//! to. The node of every CPU is read from sysfs once, the first time it is needed. Outside Linux,
+//! every thread is reported on CPU 0 of node 0, as are CPUs which can't be placed, so locality
+//! never changes which waiter is served there.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::OnceLock;
+
+/// The directory listing the machine's NUMA nodes and the CPUs in each.
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// The NUMA node of each CPU, indexed by CPU number.
+static NODES: OnceLock<Vec<usize>> = OnceLock::new();
+
+/// A CPU, and the NUMA node it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub(crate) cpu: usize,
+    pub(crate) node: usize,
+}
+
+/// Returns where the current thread last ran.
+pub(crate) fn current() -> Location {
+    let cpu = current_cpu();
+    let node = NODES.get_or_init(read_nodes).get(cpu).copied().unwrap_or(0);
+    Location { cpu, node }
+}
+
+#[cfg(target_os = "linux")]
+fn current_cpu() -> usize {
+    // SAFETY: `sched_getcpu` has no preconditions; it returns -1 on failure, which is mapped to
+    // CPU 0 below.
+    let cpu = unsafe { libc::sched_getcpu() };
+    usize::try_from(cpu).unwrap_or(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_cpu() -> usize {
+    0
+}
+
+/// Reads the node of each CPU from sysfs.
+fn read_nodes() -> Vec<usize> {
+    let mut nodes = Vec::new();
+    let entries = match fs::read_dir(NODE_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return nodes,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let node = match name.to_str().and_then(|n| n.strip_prefix("node")) {
+            Some(node) => match node.parse() {
+                Ok(node) => node,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if let Ok(list) = fs::read_to_string(entry.path().join("cpulist")) {
+            for cpu in parse_cpu_list(&list) {
+                if nodes.len() <= cpu {
+                    nodes.resize(cpu + 1, 0);
+                }
+                nodes[cpu] = node;
+            }
+        }
+    }
+    nodes
+}
+
+/// Parses a kernel CPU list such as `0-3,8,10-11`, skipping any malformed entries.
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>(), last.parse()),
+            None => (part.parse::<usize>(), part.parse()),
+        };
+        if let (Ok(first), Ok(last)) = (first, last) {
+            cpus.extend(first..=last);
+        }
+    }
+    cpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5"), vec![5]);
+        assert_eq!(parse_cpu_list("x,2-q,4"), vec![4]);
+        assert!(parse_cpu_list("\n").is_empty());
+    }
+}
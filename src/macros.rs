@@ -0,0 +1,64 @@
+/// Acquires a resource from each of several semaphores, binds the guards to the given names and
+/// runs a block, releasing every resource when the block exits.
+///
+/// Each semaphore expression must dereference to a `Semaphore`. The semaphores are acquired with
+/// `Semaphore::acquire_all`, so the same set of semaphores can be named in any order by concurrent
+/// callers without deadlocking. The resources are released however the block exits, including
+/// `return`, `?` and panics.
+///
+/// For example, `with_permits!([r = &reads, w = &writes] => { ... })` holds a resource from each
+/// of `reads` and `writes` for the duration of the block.
+#[macro_export]
+macro_rules! with_permits {
+    ([$($name:ident = $sem:expr),+ $(,)?] => $body:block) => {{
+        let mut guards = $crate::semaphore::Semaphore::acquire_all(&[$(&*$sem),+]).into_iter();
+        $(let $name = guards.next().unwrap();)+
+        $body
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::semaphore::Semaphore;
+
+    fn hold_both(a: &Semaphore, b: &Semaphore, early: bool) -> isize {
+        with_permits!([_a = a, _b = b] => {
+            if early {
+                return -1;
+            }
+            a.available_permits() + b.available_permits()
+        })
+    }
+
+    #[test]
+    fn test_with_permits() {
+        let (a, b) = (Semaphore::new(1), Semaphore::new(2));
+        assert_eq!(hold_both(&a, &b, false), 1);
+        assert_eq!(a.available_permits(), 1);
+        assert_eq!(b.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_with_permits_early_return() {
+        let (a, b) = (Semaphore::new(1), Semaphore::new(2));
+        assert_eq!(hold_both(&a, &b, true), -1);
+        assert_eq!(a.available_permits(), 1);
+        assert_eq!(b.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_with_permits_arcs() {
+        let (a, b) = (Arc::new(Semaphore::new(1)), Arc::new(Semaphore::new(1)));
+        let guards = 2;
+        let held = with_permits!([ga = a, gb = b] => {
+            drop(ga);
+            assert_eq!(a.available_permits(), 1);
+            drop(gb);
+            guards
+        });
+        assert_eq!(held, 2);
+        assert_eq!(b.available_permits(), 1);
+    }
+}
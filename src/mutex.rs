@@ -0,0 +1,156 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// A mutual exclusion lock which is handed to waiting threads in the order they asked for it.
+///
+/// The lock is a fair semaphore with a single resource, so unlike `std::sync::Mutex`, which makes
+/// no fairness guarantee, a thread which starts waiting for the lock is never overtaken by one
+/// which starts waiting later. The value is only reachable through the guard returned by `lock`,
+/// which owns the lock until it is dropped. In debug builds, a thread locking the mutex again
+/// while it holds it panics instead of deadlocking. The lock isn't poisoned by a holder panicking.
+pub struct SemMutex<T> {
+    /// The fair single-resource semaphore deciding which thread holds the lock.
+    sem: Semaphore,
+    /// The protected value. Only the holder of the semaphore's resource ever locks it, so locking
+    /// it never blocks.
+    value: Mutex<T>,
+}
+
+/// An RAII guard giving exclusive access to the value of a `SemMutex`, which unlocks it when
+/// dropped.
+pub struct SemMutexGuard<'a, T> {
+    /// The protected value. It is declared first so that it is unlocked before the resource is
+    /// released.
+    value: MutexGuard<'a, T>,
+    /// The guard holding the semaphore's only resource.
+    _guard: SemaphoreGuard<'a>,
+}
+
+impl<T> SemMutex<T> {
+    /// Wraps `value` in an unlocked mutex.
+    pub fn new(value: T) -> Self {
+        SemMutex {
+            sem: Semaphore::fair(1),
+            value: Mutex::new(value),
+        }
+    }
+
+    /// Locks the mutex, blocking until it is available, and returns a guard giving access to the
+    /// value.
+    ///
+    /// Threads blocked here are given the lock in the order they arrived.
+    pub fn lock(&self) -> SemMutexGuard<'_, T> {
+        let guard = self.sem.access();
+        self.guarded(guard)
+    }
+
+    /// Attempts to lock the mutex without waiting, returning a guard if it was unlocked and no
+    /// thread was already waiting for it.
+    pub fn try_lock(&self) -> Option<SemMutexGuard<'_, T>> {
+        self.sem.try_access().map(|guard| self.guarded(guard))
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking since the mutex is
+    /// borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Consumes the mutex, returning the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.value
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Makes the guard for the value, on behalf of the holder of the semaphore's resource.
+    fn guarded<'a>(&'a self, guard: SemaphoreGuard<'a>) -> SemMutexGuard<'a, T> {
+        SemMutexGuard {
+            value: self.value.lock().unwrap_or_else(PoisonError::into_inner),
+            _guard: guard,
+        }
+    }
+}
+
+impl<'a, T> Deref for SemMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T> DerefMut for SemMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sem_mutex_exclusion() {
+        let mutex = Arc::new(SemMutex::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let mut value = mutex.lock();
+                        let read = *value;
+                        thread::yield_now();
+                        *value = read + 1;
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*mutex.lock(), 800);
+
+        let held = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        drop(held);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_sem_mutex_fifo() {
+        let mutex = Arc::new(SemMutex::new(Vec::new()));
+        let held = mutex.lock();
+
+        // Each thread is given time to block on the lock before the next arrives.
+        let handles: Vec<_> = (0..5)
+            .map(|i| {
+                let mutex = mutex.clone();
+                let handle = thread::spawn(move || mutex.lock().push(i));
+                thread::sleep(Duration::from_millis(20));
+                handle
+            })
+            .collect();
+        drop(held);
+        for h in handles {
+            h.join().unwrap();
+        }
+        let mutex = Arc::try_unwrap(mutex).ok().unwrap();
+        assert_eq!(mutex.into_inner(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "already holds")]
+    fn test_sem_mutex_relock() {
+        let mutex = SemMutex::new(0);
+        let _held = mutex.lock();
+        let _again = mutex.lock();
+    }
+}
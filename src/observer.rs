@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+/// Receives events from a semaphore, for collecting metrics.
+///
+/// Callbacks are invoked after the semaphore's internal lock has been released, from whichever
+/// thread performed the operation, so they may be called concurrently and may block without
+/// stalling other users of the semaphore. Every method has a default implementation which does
+/// nothing, so observers only need to implement the events they care about.
+pub trait SemaphoreObserver: Send + Sync {
+    /// Called after `n` resources have been acquired, with how long the acquisition waited for
+    /// them.
+    fn on_acquire(&self, _n: isize, _waited: Duration) {}
+
+    /// Called after `n` resources have been released.
+    fn on_release(&self, _n: isize) {}
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// A budget of resources divided among named partitions.
+///
+/// Each partition is backed by its own semaphore, so acquisitions from one partition can never
+/// exceed its share. Spare resources can be moved between partitions at runtime with `rebalance`,
+/// and the total across all partitions, including resources in use, never exceeds the budget the
+/// partition was created with.
+pub struct SemaphorePartition {
+    /// The semaphore backing each partition.
+    partitions: HashMap<String, Semaphore>,
+    /// The total budget divided among the partitions.
+    total: isize,
+}
+
+impl SemaphorePartition {
+    /// Creates a partition with the given shares, whose sum is the total budget.
+    pub fn new(shares: &[(&str, isize)]) -> Self {
+        let mut sems: Vec<_> = shares.iter().map(|&(_, n)| Semaphore::new(n)).collect();
+        // The partitions divide one budget, so holding several of them at once is checked as
+        // holding a single semaphore, whichever order they were acquired in.
+        if let Some((first, rest)) = sems.split_first_mut() {
+            for sem in rest {
+                sem.share_order(first);
+            }
+        }
+        SemaphorePartition {
+            partitions: shares
+                .iter()
+                .map(|&(name, _)| name.to_string())
+                .zip(sems)
+                .collect(),
+            total: shares.iter().map(|&(_, n)| n).sum(),
+        }
+    }
+
+    /// Returns the total budget divided among the partitions.
+    pub fn total(&self) -> isize {
+        self.total
+    }
+
+    /// Acquires a resource from the named partition, blocking until one is available, and
+    /// returns an RAII guard releasing it back to that partition.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no partition with the given name.
+    pub fn acquire(&self, partition: &str) -> SemaphoreGuard<'_> {
+        self.get(partition).access()
+    }
+
+    /// Attempts to acquire a resource from the named partition without waiting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no partition with the given name.
+    pub fn try_acquire(&self, partition: &str) -> Option<SemaphoreGuard<'_>> {
+        self.get(partition).try_access()
+    }
+
+    /// Moves `n` spare resources from the partition `from` to the partition `to`.
+    ///
+    /// Only resources that are currently available in `from` can be moved. Returns `false`, and
+    /// moves nothing, if `from` has fewer than `n` available.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either partition does not exist.
+    pub fn rebalance(&self, from: &str, to: &str, n: isize) -> bool {
+        // The resources are held by neither partition while they are moved, so the total cannot
+        // be exceeded.
+        self.get(from).transfer(self.get(to), n)
+    }
+
+    fn get(&self, partition: &str) -> &Semaphore {
+        match self.partitions.get(partition) {
+            Some(sem) => sem,
+            None => panic!("no partition named {:?}", partition),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_acquire() {
+        let p = SemaphorePartition::new(&[("reads", 2), ("writes", 1)]);
+        assert_eq!(p.total(), 3);
+
+        let _w = p.acquire("writes");
+        assert!(p.try_acquire("writes").is_none());
+
+        let _r1 = p.acquire("reads");
+        let _r2 = p.acquire("reads");
+        assert!(p.try_acquire("reads").is_none());
+    }
+
+    #[test]
+    fn test_partition_rebalance() {
+        let p = SemaphorePartition::new(&[("reads", 2), ("writes", 1)]);
+
+        let r = p.acquire("reads");
+        let _w = p.acquire("writes");
+
+        // Only one of the read resources is spare.
+        assert!(!p.rebalance("reads", "writes", 2));
+        assert!(p.rebalance("reads", "writes", 1));
+        assert!(p.try_acquire("reads").is_none());
+
+        let _w2 = p.try_acquire("writes").unwrap();
+        assert!(p.try_acquire("writes").is_none());
+
+        drop(r);
+        assert!(p.try_acquire("reads").is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "no partition named")]
+    fn test_partition_unknown() {
+        let p = SemaphorePartition::new(&[("reads", 1)]);
+        p.acquire("writes");
+    }
+}
@@ -0,0 +1,150 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// A pool of reusable resources, with a semaphore counting how many are idle.
+pub struct Pool<T> {
+    /// One resource per idle item in the pool.
+    sem: Semaphore,
+    /// The items not currently handed out.
+    idle: Mutex<Vec<T>>,
+}
+
+/// An RAII guard giving access to a resource taken from a `Pool`.
+///
+/// When dropped, a pooled resource is returned to the pool and its permit released. Overflow
+/// resources created by `Pool::try_get_or` hold no permit and are simply dropped.
+pub struct PooledGuard<'a, T> {
+    /// The pool the resource was taken from.
+    pool: &'a Pool<T>,
+    /// The resource, only `None` while the guard is being dropped.
+    value: Option<T>,
+    /// The permit for a pooled resource, or `None` for an overflow resource.
+    permit: Option<SemaphoreGuard<'a>>,
+}
+
+impl<T> Pool<T> {
+    /// Creates a pool holding the given items.
+    pub fn new(items: Vec<T>) -> Self {
+        Pool {
+            sem: Semaphore::new(items.len() as isize),
+            idle: Mutex::new(items),
+        }
+    }
+
+    /// Takes a resource from the pool, blocking until one is idle.
+    pub fn get(&self) -> PooledGuard<'_, T> {
+        let permit = self.sem.access();
+        self.take(permit)
+    }
+
+    /// Takes a resource from the pool if one is idle, without waiting.
+    pub fn try_get(&self) -> Option<PooledGuard<'_, T>> {
+        self.sem.try_access().map(|permit| self.take(permit))
+    }
+
+    /// Takes a resource from the pool if one is idle, otherwise creates a temporary overflow
+    /// resource with `make`.
+    ///
+    /// Overflow resources are dropped rather than returned to the pool when their guard is
+    /// dropped, so demand spikes are absorbed without permanently growing the pool.
+    pub fn try_get_or<F>(&self, make: F) -> PooledGuard<'_, T>
+    where
+        F: FnOnce() -> T,
+    {
+        self.try_get().unwrap_or_else(|| PooledGuard {
+            pool: self,
+            value: Some(make()),
+            permit: None,
+        })
+    }
+
+    /// Returns the number of items currently idle in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Takes an idle item out of the pool on behalf of a permit holder.
+    fn take<'a>(&'a self, permit: SemaphoreGuard<'a>) -> PooledGuard<'a, T> {
+        // Holding a permit guarantees that an item is idle.
+        let value = self.idle.lock().unwrap().pop();
+        PooledGuard {
+            pool: self,
+            value,
+            permit: Some(permit),
+        }
+    }
+}
+
+impl<'a, T> PooledGuard<'a, T> {
+    /// Returns whether this is a temporary overflow resource which will not be returned to the
+    /// pool.
+    pub fn is_overflow(&self) -> bool {
+        self.permit.is_none()
+    }
+}
+
+impl<'a, T> Deref for PooledGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for PooledGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+// Return pooled items to the pool before their permit is released, so that the item is idle by the
+// time another thread can acquire it.
+impl<'a, T> Drop for PooledGuard<'a, T> {
+    fn drop(&mut self) {
+        if let (Some(value), Some(_)) = (self.value.take(), &self.permit) {
+            self.pool.idle.lock().unwrap().push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_get() {
+        let pool = Pool::new(vec![1, 2]);
+        let a = pool.get();
+        let b = pool.get();
+        assert_eq!(*a + *b, 3);
+        assert!(pool.try_get().is_none());
+        drop(a);
+        assert_eq!(pool.idle_count(), 1);
+        assert!(pool.try_get().is_some());
+    }
+
+    #[test]
+    fn test_pool_try_get_or_overflow() {
+        let pool = Pool::new(vec![String::from("pooled")]);
+
+        let pooled = pool.try_get_or(|| String::from("overflow"));
+        assert!(!pooled.is_overflow());
+
+        let mut overflow = pool.try_get_or(|| String::from("overflow"));
+        assert!(overflow.is_overflow());
+        overflow.push('!');
+        assert_eq!(&*overflow, "overflow!");
+
+        drop(overflow);
+        assert_eq!(pool.idle_count(), 0);
+        assert!(pool.try_get().is_none());
+
+        drop(pooled);
+        assert_eq!(pool.idle_count(), 1);
+        let again = pool.try_get().unwrap();
+        assert_eq!(&*again, "pooled");
+        assert!(pool.try_get().is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Mutex;
+
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+
+/// What a `ProbingSemaphore` observed over a window of acquisitions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeWindow {
+    /// The number of acquisitions in the window.
+    pub acquisitions: usize,
+    /// The number of those acquisitions which found no resource available and had to wait.
+    pub waited: usize,
+}
+
+/// Decides how a `ProbingSemaphore` moves its limit after each window of acquisitions.
+pub trait ProbePolicy: Send + Sync {
+    /// Returns the next limit given the current one and what the last window observed, before it
+    /// is clamped between one and the semaphore's ceiling.
+    fn next_limit(&self, limit: isize, window: &ProbeWindow) -> isize;
+}
+
+/// Probes exponentially: multiplies the limit by `growth` after a window in which no acquisition
+/// had to wait, and by `backoff` after a window in which any did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exponential {
+    /// The factor the limit grows by while the semaphore keeps up.
+    pub growth: f64,
+    /// The factor the limit shrinks by once acquisitions start waiting.
+    pub backoff: f64,
+}
+
+impl Default for Exponential {
+    /// Doubles the limit while it keeps up, and halves it on saturation.
+    fn default() -> Self {
+        Exponential {
+            growth: 2.0,
+            backoff: 0.5,
+        }
+    }
+}
+
+impl ProbePolicy for Exponential {
+    fn next_limit(&self, limit: isize, window: &ProbeWindow) -> isize {
+        if window.waited > 0 {
+            (limit as f64 * self.backoff).floor() as isize
+        } else {
+            ((limit as f64 * self.growth).ceil() as isize).max(limit + 1)
+        }
+    }
+}
+
+/// A semaphore which starts small and probes for a higher limit while it keeps up with demand.
+///
+/// This is a ramp-up explorer for when the right concurrency limit isn't known up front, and a
+/// simpler cousin of `AdaptiveSemaphore`. After every window of acquisitions, the policy picks the
+/// next limit from how many of them had to wait for a resource: none waiting means the limit
+/// isn't holding anyone back, so it may be raised with `Semaphore::add_permits`, while waiting
+/// acquisitions are a sign of saturation, which may lower it with `Semaphore::forget`. The limit
+/// always stays between one and the ceiling.
+pub struct ProbingSemaphore {
+    /// The semaphore whose resources are handed out.
+    sem: Semaphore,
+    /// Picks the next limit after each window.
+    policy: Box<dyn ProbePolicy>,
+    /// The limit is never raised above this.
+    ceiling: isize,
+    /// The number of acquisitions between adjustments.
+    window: usize,
+    /// The current limit and the window in progress, together so that adjustments are
+    /// serialized.
+    state: Mutex<ProbeState>,
+}
+
+/// The current limit of a `ProbingSemaphore` and the window in progress.
+struct ProbeState {
+    limit: isize,
+    window: ProbeWindow,
+}
+
+impl ProbingSemaphore {
+    /// Creates a probing semaphore starting with `initial` resources, which adjusts its limit
+    /// every `window` acquisitions using `policy`, never going above `ceiling`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `initial` isn't between one and `ceiling`, or if `window` is zero.
+    pub fn new<P>(initial: isize, ceiling: isize, window: usize, policy: P) -> Self
+    where
+        P: ProbePolicy + 'static,
+    {
+        assert!(
+            1 <= initial && initial <= ceiling,
+            "initial limit must be between one and the ceiling"
+        );
+        assert!(window > 0, "probing window must not be empty");
+        ProbingSemaphore {
+            sem: Semaphore::new(initial),
+            policy: Box::new(policy),
+            ceiling,
+            window,
+            state: Mutex::new(ProbeState {
+                limit: initial,
+                window: ProbeWindow {
+                    acquisitions: 0,
+                    waited: 0,
+                },
+            }),
+        }
+    }
+
+    /// Returns the current limit on concurrent acquisitions.
+    pub fn limit(&self) -> isize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Acquires a resource, blocking until one is available, and adjusts the limit if this
+    /// completes a window.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        let (guard, waited) = match self.sem.try_access() {
+            Some(guard) => (guard, false),
+            None => (self.sem.access(), true),
+        };
+        self.record(waited);
+        guard
+    }
+
+    /// Records an acquisition, and adjusts the limit at the end of a window.
+    fn record(&self, waited: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.window.acquisitions += 1;
+        state.window.waited += usize::from(waited);
+        if state.window.acquisitions < self.window {
+            return;
+        }
+        let finished = std::mem::replace(
+            &mut state.window,
+            ProbeWindow {
+                acquisitions: 0,
+                waited: 0,
+            },
+        );
+
+        let next = self
+            .policy
+            .next_limit(state.limit, &finished)
+            .max(1)
+            .min(self.ceiling);
+        if next > state.limit {
+            self.sem.add_permits(next - state.limit);
+            state.limit = next;
+        } else if next < state.limit {
+            state.limit -= self.sem.forget(state.limit - next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_probe_ramps_to_ceiling() {
+        let sem = ProbingSemaphore::new(1, 16, 4, Exponential::default());
+        let mut limits = Vec::new();
+        for _ in 0..6 {
+            for _ in 0..4 {
+                drop(sem.access());
+            }
+            limits.push(sem.limit());
+        }
+        assert_eq!(limits, vec![2, 4, 8, 16, 16, 16]);
+    }
+
+    #[test]
+    fn test_probe_backs_off_when_saturated() {
+        let sem = Arc::new(ProbingSemaphore::new(4, 16, 6, Exponential::default()));
+        let held: Vec<_> = (0..4).map(|_| sem.access()).collect();
+
+        // Every resource is held, so the next acquisition has to wait.
+        let waiter = sem.clone();
+        let t = thread::spawn(move || drop(waiter.access()));
+        thread::sleep(Duration::from_millis(20));
+        drop(held);
+        t.join().unwrap();
+        assert_eq!(sem.limit(), 4);
+
+        // The acquisition completing the window halves the limit.
+        drop(sem.access());
+        assert_eq!(sem.limit(), 2);
+    }
+
+    #[test]
+    fn test_probe_custom_policy() {
+        /// Grows the limit by one per window, whatever happened.
+        struct Linear;
+
+        impl ProbePolicy for Linear {
+            fn next_limit(&self, limit: isize, _window: &ProbeWindow) -> isize {
+                limit + 1
+            }
+        }
+
+        let sem = ProbingSemaphore::new(1, 3, 1, Linear);
+        for _ in 0..4 {
+            drop(sem.access());
+        }
+        assert_eq!(sem.limit(), 3);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::semaphore::Semaphore;
+
+/// A queue holding at most a fixed number of items, whose producers block while it is full and
+/// whose consumers block while it is empty.
+///
+/// One semaphore counts the free slots and another the queued items, so a full queue applies
+/// backpressure to producers without any polling.
+pub struct BoundedQueue<T> {
+    /// One resource per free slot.
+    slots: Semaphore,
+    /// One resource per queued item.
+    items: Semaphore,
+    /// The queued items, oldest first.
+    queue: Mutex<VecDeque<T>>,
+}
+
+impl<T> BoundedQueue<T> {
+    /// Creates an empty queue holding at most `capacity` items.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is greater than `isize::MAX`.
+    pub fn new(capacity: usize) -> Self {
+        BoundedQueue {
+            // A producer may wait for a slot it filled itself to be freed by a consumer.
+            slots: Semaphore::new_capacity(capacity).for_signalling(),
+            items: Semaphore::new(0),
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds an item to the back of the queue, blocking while the queue is full.
+    pub fn push(&self, item: T) {
+        self.slots.acquire();
+        self.queue.lock().unwrap().push_back(item);
+        self.items.release();
+    }
+
+    /// Adds an item to the back of the queue if there is room, otherwise handing it back.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        if !self.slots.try_acquire() {
+            return Err(item);
+        }
+        self.queue.lock().unwrap().push_back(item);
+        self.items.release();
+        Ok(())
+    }
+
+    /// Removes the item at the front of the queue, blocking while the queue is empty.
+    pub fn pop(&self) -> T {
+        self.items.acquire();
+        self.take()
+    }
+
+    /// Removes the item at the front of the queue if there is one, without waiting.
+    pub fn try_pop(&self) -> Option<T> {
+        if self.items.try_acquire() {
+            Some(self.take())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of items currently queued.
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Returns whether the queue is currently empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the front item on behalf of a consumer holding an item resource, and frees its slot.
+    fn take(&self) -> T {
+        // Holding an item resource guarantees that an item is queued.
+        let item = self.queue.lock().unwrap().pop_front().unwrap();
+        self.slots.release();
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_push_pop() {
+        let queue = BoundedQueue::new(2);
+        queue.push(1);
+        assert_eq!(queue.try_push(2), Ok(()));
+        assert_eq!(queue.try_push(3), Err(3));
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.pop(), 1);
+        assert_eq!(queue.try_pop(), Some(2));
+        assert_eq!(queue.try_pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_queue_single_slot_producer() {
+        // The producer waits on a slot it filled itself until the consumer frees it.
+        let queue = Arc::new(BoundedQueue::new(1));
+        let consumer = queue.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            (consumer.pop(), consumer.pop())
+        });
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(t.join().unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn test_queue_push_blocks_when_full() {
+        let queue = Arc::new(BoundedQueue::new(1));
+        queue.push("first");
+
+        let (tx, rx) = channel();
+        let producer = queue.clone();
+        let t = thread::spawn(move || {
+            producer.push("second");
+            tx.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(queue.pop(), "first");
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(queue.pop(), "second");
+    }
+}
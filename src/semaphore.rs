@@ -1,98 +1,6291 @@
-use std::ops::Drop;
-use std::sync::{Condvar, Mutex};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Write as _};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::marker::PhantomData;
+use std::ops::{Deref, Drop};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock, PoisonError, TryLockError};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancelScope;
+#[cfg(any(
+    all(feature = "lock-order", debug_assertions),
+    feature = "nested-holds"
+))]
+use crate::holds::{self, HoldKey};
+use crate::observer::SemaphoreObserver;
+use crate::store::{InMemory, PermitStore};
+use crate::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use crate::sync::{Condvar, Mutex, MutexGuard};
+use crate::wait_queue::WaitQueue;
 
 /// A counting, blocking, semaphore.
 ///
 /// Semaphores are a form of atomic counter where access is only granted if the counter is a
 /// positive value. Each acquisition blocks the calling thread until the counter is positive. Each
 /// release increments the counter and unblocks any threads if necessary.
-pub struct Semaphore {
-    /// The counter, wrapped in a Mutex to ensure atomicity.
-    counter: Mutex<isize>,
+///
+/// # Memory ordering
+///
+/// A release happens-before every acquisition which takes the resource it released, so writes a
+/// thread makes before releasing are visible to the thread which acquires the resource next,
+/// as if they had handed over a `Mutex`. This holds on every path: the count is guarded by a
+/// mutex, and releases which skip it (`fast_release` and coalesced releases) publish through
+/// atomic read-modify-write operations with at least release ordering, which are read with
+/// acquire ordering before the resource is added to the count under the mutex.
+///
+/// # Stores
+///
+/// A semaphore created with `with_store` keeps its count in a `PermitStore` rather than in
+/// memory, and only offers the core acquire and release operations. Its waiting threads are woken
+/// by releases through the semaphore, and poll the store for resources released to it elsewhere.
+pub struct Semaphore<S: PermitStore = InMemory> {
+    /// The semaphore's state, wrapped in a Mutex to ensure atomicity.
+    state: Mutex<State>,
     /// The condvar notifies any threads that are blocked waiting on the semaphore.
     condvar: Condvar,
+    /// Notifies threads waiting for the semaphore to become idle. This is kept separate from
+    /// `condvar` so that waking an idle waiter never uses up a notification meant for a thread
+    /// waiting on a resource.
+    idle: Condvar,
+    /// Callbacks invoked when the count crosses zero, if any.
+    edges: Option<EdgeCallbacks>,
+    /// Whether every acquisition goes through the wait queue, rather than only priority ones.
+    fair: bool,
+    /// Whether releases notify waiting threads after unlocking the state rather than before.
+    notify_after_unlock: bool,
+    /// Whether concurrent releases are coalesced through `pending_releases`.
+    coalesce_releases: bool,
+    /// Whether releasing several resources wakes only as many threads, rather than all of them.
+    exact_wakeups: bool,
+    /// Resources released by coalescing releases which are yet to be added to the count.
+    pending_releases: AtomicIsize,
+    /// Resources released by `fast_release` which are yet to be added to the count. They are
+    /// added whenever the state is next locked.
+    fast_releases: AtomicIsize,
+    /// The number of threads blocked on either condvar or parked in `acquire_parkable`, which
+    /// `fast_release` must wake.
+    sleepers: AtomicUsize,
+    /// The number of threads blocked waiting to acquire resources, as counted by `start_waiting`.
+    /// It is only changed with the state locked, but kept outside the lock so that `waiter_count`
+    /// needn't take it.
+    waiters: AtomicUsize,
+    /// The number of threads blocked waiting to take more than one resource at once, or an amount
+    /// computed as they go, or pulsing a `PulseGuard`, which a release must wake all threads for.
+    /// Like `waiters`, it is only changed with the state locked.
+    batch_waiters: AtomicUsize,
+    /// The number of registered async wakers, mirrored outside the lock for `fast_release`.
+    #[cfg(feature = "async")]
+    registered_wakers: AtomicUsize,
+    /// The observer receiving this semaphore's events, if any. It has its own lock so that it can
+    /// be swapped, and events delivered, without holding up the semaphore's state.
+    observer: Mutex<Option<Arc<dyn SemaphoreObserver>>>,
+    /// Whether `observer` is set, so that unobserved operations needn't take its lock.
+    observed: AtomicBool,
+    /// The counters exported by `render_prometheus`, if the semaphore was given a metrics name.
+    metrics: Option<Metrics>,
+    /// The number of releases which have started but may not have reached the count yet, which
+    /// `sync_point` waits for.
+    #[cfg(feature = "testing")]
+    in_flight: AtomicUsize,
+    /// The longest any acquisition has waited, in nanoseconds. This is always a std atomic, since
+    /// whether it is updated depends on real time, which a model checker can't replay.
+    max_wait_nanos: std::sync::atomic::AtomicU64,
+    /// The key this semaphore's holds are tracked under, including its place in the global lock
+    /// order.
+    #[cfg(any(
+        all(feature = "lock-order", debug_assertions),
+        feature = "nested-holds"
+    ))]
+    holds: HoldKey,
+    /// Whether a thread has acquired from this semaphore while already holding a resource from it.
+    #[cfg(feature = "nested-holds")]
+    nested_hold: AtomicBool,
+    /// The store keeping the count, unless it is kept in `state`.
+    store: S,
+    /// How often threads waiting on the store poll it for resources released elsewhere.
+    store_poll: Duration,
+}
+
+/// The process-wide semaphores handed out by `Semaphore::named`. This is always a std mutex, since
+/// a loom mutex can't live in a static.
+static NAMED: OnceLock<std::sync::Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+
+/// A builder for semaphores combining several options, created by `Semaphore::builder`.
+#[derive(Debug, Clone)]
+pub struct SemaphoreBuilder {
+    /// The initial count.
+    count: isize,
+    /// The order waiting threads are served in.
+    ordering: WaiterOrder,
+    /// The maximum count, as `Semaphore::with_max`.
+    max: Option<isize>,
+    /// The floor the semaphore can't be shrunk below, as `Semaphore::with_min`.
+    min: isize,
+    /// The most threads which may block at once, as `Semaphore::with_max_waiters`.
+    max_waiters: Option<usize>,
+    /// The rate priority waiters age at, as `Semaphore::with_priority_aging`.
+    aging_rate: f64,
+    /// Whether releases notify waiting threads after unlocking the state.
+    notify_after_unlock: bool,
+    /// Whether concurrent releases are coalesced.
+    coalesce_releases: bool,
+    /// Whether releasing several resources wakes only as many threads.
+    exact_wakeups: bool,
+    /// Whether re-acquiring the only resource a thread holds panics in debug builds.
+    detect_reentrant_acquire: bool,
+    /// Whether queued waiters near the releasing thread are preferred.
+    #[cfg(feature = "numa")]
+    prefer_local_waiters: bool,
+}
+
+/// The order in which a semaphore serves the threads waiting on it.
+///
+/// The ordering only decides who gets a resource when threads are already waiting for one;
+/// whatever the ordering, a thread that doesn't have to wait takes a resource straight away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaiterOrder {
+    /// Waiting threads are served in arrival order, as on a `Semaphore::fair` semaphore. No
+    /// thread can be starved, but every handoff goes through the wait queue, and a thread
+    /// arriving while others wait can never take a resource ahead of them, which costs
+    /// throughput under contention.
+    Fifo,
+    /// The most recently arrived waiting thread is served first. Recently parked threads are the
+    /// likeliest to still have warm caches, but a thread can be starved for as long as newer ones
+    /// keep arriving.
+    Lifo,
+    /// Whichever thread reaches the semaphore first after a release is served, as on a plain
+    /// `Semaphore::new` semaphore. This gives the best throughput, since threads never wait
+    /// behind a queue, but makes no fairness guarantees at all.
+    Unordered,
+}
+
+/// The threads blocked on a semaphore's condvar which a change to its state must wake.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Wakeup {
+    None,
+    /// At most this many threads, each of which takes one resource.
+    Some(usize),
+    All,
+}
+
+/// The sending half of a channel subscribed to a semaphore's resources becoming available.
+enum Subscriber {
+    /// A channel created by `Semaphore::subscribe`.
+    Std(SyncSender<()>),
+    /// A channel created by `Semaphore::subscribe_crossbeam`.
+    #[cfg(feature = "crossbeam")]
+    Crossbeam(crossbeam_channel::Sender<()>),
+}
+
+/// Callbacks invoked when a semaphore's count transitions between positive and non-positive.
+struct EdgeCallbacks {
+    /// Invoked when an acquisition takes the last available resource.
+    on_exhausted: Box<dyn Fn() + Send + Sync>,
+    /// Invoked when a release makes a resource available again.
+    on_available: Box<dyn Fn() + Send + Sync>,
+}
+
+/// Totals counted for a semaphore created by `Semaphore::with_metrics_name`.
+struct Metrics {
+    /// The prefix of every exported metric.
+    name: String,
+    /// The number of resources acquired.
+    acquired: AtomicU64,
+    /// The number of resources released.
+    released: AtomicU64,
+}
+
+/// The state of a semaphore which must be modified atomically.
+struct State {
+    /// The number of available resources.
+    count: isize,
+    /// The threads queued by priority acquisitions.
+    queue: WaitQueue,
+    /// The maximum the count may reach, if any.
+    capacity: Option<isize>,
+    /// The count at which no resources are held: the capacity if there is one, otherwise the
+    /// initial count.
+    idle: isize,
+    /// The fewest resources `forget` and `shrink_to` may leave the semaphore with in total.
+    min: isize,
+    /// The number of acquisitions which had to wait for a resource.
+    contended_acquires: u64,
+    /// Whether the semaphore has been closed.
+    closed: bool,
+    /// The thread which last took the only resource of a semaphore with a single resource, for
+    /// catching it trying to acquire the resource again.
+    #[cfg(debug_assertions)]
+    holder: Option<crate::sync::ThreadId>,
+    /// Whether `holder` is tracked, which semaphores used for signalling turn off.
+    #[cfg(debug_assertions)]
+    track_holder: bool,
+    /// Invoked for each resource released by its holder after the semaphore was closed by
+    /// `close_with`.
+    on_orphan: Option<Box<dyn Fn() + Send + Sync>>,
+    /// The number of threads blocked in `wait_for_available`, which every release must wake since
+    /// they never take the resources they are woken for.
+    watchers: usize,
+    /// The most threads which may be blocked in `acquire` or `acquire_priority` at once, if
+    /// bounded.
+    max_waiters: Option<usize>,
+    /// The senders of channels subscribed to resources becoming available.
+    subscribers: Vec<Subscriber>,
+    /// The sender publishing the count to the receivers returned by `watch`, once there are any.
+    #[cfg(feature = "async")]
+    count_watch: Option<tokio::sync::watch::Sender<isize>>,
+    /// The threads parked in `acquire_parkable`, keyed by an id unique to each registration.
+    parked: Vec<(u64, thread::Thread)>,
+    /// The id of the next parked thread registration.
+    next_parked: u64,
+    /// The consumers registered by `register_handoff`, keyed by the id of their ticket.
+    handoffs: Vec<(u64, Handoff)>,
+    /// The id of the next handoff registration.
+    next_handoff: u64,
+    /// The tickets of the acquisitions started by `start_acquire` which are not being completed
+    /// yet, and whether each has been handed its resource.
+    pending: Vec<(u64, bool)>,
+    /// Bumped by every release, so that a thread which found the store empty can tell whether a
+    /// resource may have been given back to it since.
+    store_generation: u64,
+    /// The wakers of pending async acquisitions, keyed by an id unique to each registration.
+    #[cfg(feature = "async")]
+    wakers: Vec<(u64, Waker)>,
+    /// The id of the next waker registration.
+    #[cfg(feature = "async")]
+    next_waker: u64,
+}
+
+/// A consumer registered to be handed a resource directly by `Semaphore::release_to`.
+struct Handoff {
+    /// Whether a resource has been handed to the consumer, which it has yet to take.
+    granted: bool,
+    /// The consumer's thread while it waits in `HandoffToken::wait`.
+    waiter: Option<thread::Thread>,
+}
+
+/// How a waiting thread behaves between being woken and retrying its acquisition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Retry immediately.
+    None,
+    /// Yield for a random duration between `min` and `max` before retrying, so that threads woken
+    /// together do not all contend for the internal lock at once.
+    Jitter { min: Duration, max: Duration },
+}
+
+/// An error returned when a resource cannot be acquired from a semaphore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireError {
+    /// The semaphore has been closed.
+    Closed,
+    /// The semaphore already has as many blocked waiters as it allows.
+    QueueFull,
+    /// More resources were requested than the semaphore's capacity, so the request could never
+    /// be satisfied.
+    Unsatisfiable,
+    /// The acquisition was cancelled through its `AcquireToken`.
+    Cancelled,
+}
+
+/// An error returned when a resource cannot be acquired from a semaphore without waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAccessError {
+    /// No resources are available right now.
+    NoPermits,
+    /// The semaphore has been closed.
+    Closed,
+    /// No resources are available, and the semaphore already has as many blocked waiters as it
+    /// allows, so waiting for one would fail too.
+    QueueFull,
+}
+
+/// A consistent view of a semaphore's counters, taken by `Semaphore::checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemaphoreSnapshot {
+    /// The number of resources available.
+    pub available: isize,
+    /// The maximum count, or `None` if the semaphore is uncapped.
+    pub capacity: Option<isize>,
+    /// The number of resources held, counted from the capacity if there is one, otherwise from
+    /// the initial count. Resources whose release is still in progress count as held.
+    pub outstanding: isize,
+    /// The number of threads blocked waiting to acquire resources.
+    pub waiters: usize,
+    /// The number of blocking acquisitions which had to wait for a resource.
+    pub contended_acquires: u64,
+}
+
+/// An RAII guard which will release the resources acquired from a semaphore when dropped.
+pub struct SemaphoreGuard<'a, S: PermitStore = InMemory> {
+    /// The semaphore being guarded.
+    sem: &'a Semaphore<S>,
+    /// The number of resources held by the guard.
+    permits: isize,
+}
+
+/// An RAII guard which owns a reference to a semaphore and will release a resource acquired
+/// from it when dropped.
+///
+/// Unlike `SemaphoreGuard`, this guard is not tied to a borrow of the semaphore, so it can be
+/// sent to other threads freely.
+pub struct OwnedSemaphoreGuard<S: PermitStore = InMemory> {
+    /// The semaphore being guarded.
+    sem: Arc<Semaphore<S>>,
+    /// The number of resources held by the guard.
+    permits: isize,
+}
+
+/// A stream yielding a guard for each resource it acquires from a semaphore, created by
+/// `Semaphore::permit_stream`.
+#[cfg(feature = "async")]
+pub struct PermitStream {
+    /// The semaphore to acquire from.
+    sem: Arc<Semaphore>,
+    /// The acquisition of the next resource, once the stream has been polled for it.
+    pending: Option<Acquire<Arc<Semaphore>>>,
+}
+
+/// An RAII guard holding a resource which it periodically hands to waiting threads before taking
+/// it back, created by `Semaphore::access_pulsed`.
+///
+/// The resource is released for good when the guard is dropped.
+pub struct PulseGuard {
+    /// The semaphore being guarded.
+    sem: Arc<Semaphore>,
+    /// Dropped to stop the pulsing thread.
+    stop: Option<mpsc::Sender<()>>,
+    /// The thread pulsing the resource, which returns whether it still holds it when stopped.
+    pulser: Option<thread::JoinHandle<bool>>,
+}
+
+/// An RAII guard which reports how long its resource was held before releasing it on drop.
+pub struct TimedSemaphoreGuard<'a, F: FnOnce(Duration)> {
+    /// The guard holding the resource.
+    _guard: SemaphoreGuard<'a>,
+    /// When the resource was acquired.
+    acquired: Instant,
+    /// Invoked with the hold duration on drop, only `None` once it has been invoked.
+    on_release: Option<F>,
+}
+
+/// An RAII guard which tears down the resource it guards before releasing it on drop, created by
+/// `Semaphore::access_bundle`.
+pub struct BundleGuard<'a, F: FnOnce()> {
+    /// The guard holding the resource.
+    _guard: SemaphoreGuard<'a>,
+    /// Invoked on drop before the resource is released, only `None` once it has been invoked.
+    teardown: Option<F>,
+}
+
+/// A semaphore whose resources are reserved from a parent semaphore, created by
+/// `Semaphore::fork`.
+///
+/// The child dereferences to an ordinary semaphore holding the reserved resources, so every
+/// acquisition from the child also counts against the parent's budget. Dropping the child returns
+/// the reservation to the parent.
+pub struct ChildSemaphore<'a> {
+    /// The child's own semaphore, capped at the reservation.
+    sem: Semaphore,
+    /// The resources reserved from the parent.
+    reservation: SemaphoreGuard<'a>,
+}
+
+/// A scope handing out resources which are all released by the time it ends, created by
+/// `Semaphore::scope`.
+pub struct SemaphoreScope<'scope, 'env: 'scope> {
+    /// The semaphore resources are acquired from.
+    sem: &'env Semaphore,
+    /// The resources acquired through the scope which are yet to be released.
+    held: AtomicIsize,
+    /// Makes `'scope` invariant, so that guards can't be made to outlive the scope.
+    scope: PhantomData<&'scope mut &'scope ()>,
+}
+
+/// An RAII guard for a resource acquired through a `SemaphoreScope`, which releases it when
+/// dropped and can't outlive the scope.
+pub struct ScopedGuard<'scope> {
+    /// The scope the resource was acquired through.
+    held: &'scope AtomicIsize,
+    /// The semaphore being guarded.
+    sem: &'scope Semaphore,
+}
+
+/// A pending acquisition of a resource from a semaphore, created by `Semaphore::acquire_handle`.
+///
+/// The acquisition is only carried out by `wait`, and can be abandoned with `cancel`, from another
+/// thread sharing the token or through an `AcquireCanceller`. Cancelling wakes a blocked `wait`,
+/// and a cancelled acquisition never consumes a resource.
+pub struct AcquireToken<'a> {
+    /// The semaphore to acquire from.
+    sem: &'a Semaphore,
+    /// Set once the acquisition has been cancelled.
+    cancelled: Arc<AtomicBool>,
+}
+
+/// An acquisition of a resource from a semaphore which has started but not yet completed, created
+/// by `Semaphore::start_acquire`.
+///
+/// The acquisition holds a place in the semaphore's wait queue while it is pending. Dropping it
+/// before calling `complete` gives up that place and never consumes a resource.
+pub struct PendingAcquire<'a> {
+    /// The semaphore to acquire from.
+    sem: &'a Semaphore,
+    /// The acquisition's ticket in the wait queue, only `None` once it has been completed.
+    ticket: Option<u64>,
+    /// When the acquisition started.
+    started: Instant,
 }
 
-/// An RAII guard which will release a resource acquired from a semaphore when dropped.
-pub struct SemaphoreGuard<'a> {
-    /// The semaphore being guarded.
-    sem: &'a Semaphore,
-}
+/// A consumer's registration to be handed a resource directly by a producer, created by
+/// `Semaphore::register_handoff`.
+///
+/// The producer is given the token's `ticket` and passes it to `Semaphore::release_to`, which
+/// wakes only this consumer, however many other threads are waiting. Dropping the token gives up
+/// the registration, after which a handoff to it is an ordinary release.
+pub struct HandoffToken<'a> {
+    /// The semaphore the resource is handed over through.
+    sem: &'a Semaphore,
+    /// The id of the registration.
+    id: u64,
+}
+
+/// Identifies a `HandoffToken` to the producer which hands it a resource.
+#[derive(Clone, Copy)]
+pub struct HandoffTicket<'a> {
+    /// The semaphore the token was registered with.
+    sem: &'a Semaphore,
+    /// The id of the registration.
+    id: u64,
+}
+
+/// A handle which can cancel an `AcquireToken` from another thread.
+#[derive(Clone)]
+pub struct AcquireCanceller<'a> {
+    /// The semaphore the token acquires from.
+    sem: &'a Semaphore,
+    /// Shared with the token being cancelled.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<S: PermitStore> Semaphore<S> {
+    /// Initialize a new semaphore over `store`, with `n` resources counted in memory.
+    fn from_store(store: S, n: isize, rate: f64, poll_interval: Duration) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                count: n,
+                queue: WaitQueue::new(rate),
+                capacity: None,
+                idle: n,
+                min: 0,
+                contended_acquires: 0,
+                closed: false,
+                #[cfg(debug_assertions)]
+                holder: None,
+                #[cfg(debug_assertions)]
+                track_holder: true,
+                on_orphan: None,
+                watchers: 0,
+                max_waiters: None,
+                subscribers: Vec::new(),
+                #[cfg(feature = "async")]
+                count_watch: None,
+                parked: Vec::new(),
+                next_parked: 0,
+                handoffs: Vec::new(),
+                next_handoff: 0,
+                pending: Vec::new(),
+                store_generation: 0,
+                #[cfg(feature = "async")]
+                wakers: Vec::new(),
+                #[cfg(feature = "async")]
+                next_waker: 0,
+            }),
+            condvar: Condvar::new(),
+            idle: Condvar::new(),
+            edges: None,
+            fair: false,
+            notify_after_unlock: false,
+            coalesce_releases: false,
+            exact_wakeups: true,
+            pending_releases: AtomicIsize::new(0),
+            fast_releases: AtomicIsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            waiters: AtomicUsize::new(0),
+            batch_waiters: AtomicUsize::new(0),
+            #[cfg(feature = "async")]
+            registered_wakers: AtomicUsize::new(0),
+            observer: Mutex::new(None),
+            observed: AtomicBool::new(false),
+            metrics: None,
+            #[cfg(feature = "testing")]
+            in_flight: AtomicUsize::new(0),
+            max_wait_nanos: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(any(
+                all(feature = "lock-order", debug_assertions),
+                feature = "nested-holds"
+            ))]
+            holds: HoldKey::new(),
+            #[cfg(feature = "nested-holds")]
+            nested_hold: AtomicBool::new(false),
+            store,
+            store_poll: poll_interval,
+        }
+    }
+
+    /// Initialize a new semaphore keeping its count in `store`, such as a key in an external store
+    /// shared between processes.
+    ///
+    /// Threads waiting for resources are woken by releases through this semaphore, and poll the
+    /// store every `poll_interval` to notice resources released to it some other way.
+    pub fn with_store(store: S, poll_interval: Duration) -> Self {
+        Semaphore::from_store(store, isize::MAX, 0.0, poll_interval)
+    }
+
+    /// Returns the store keeping this semaphore's count.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Acquires the resource protected by the semaphore, blocking the current thread until the
+    /// resource is actually acquired.
+    ///
+    /// If no resources are available, the thread will be blocked waiting on the resource until one
+    /// is available. Condvars are allowed to wake up spuriously, so the count is re-checked after
+    /// every wakeup and the thread goes back to waiting if it is still not positive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is closed, either before or while waiting, or if its wait queue is
+    /// full. Use `acquire_checked` if either may happen.
+    ///
+    /// In debug builds, also panics rather than deadlocking if the semaphore has a single resource
+    /// and the current thread already holds it, unless the check was turned off with
+    /// `for_signalling`.
+    pub fn acquire(&self) {
+        unwrap_acquired(self.acquire_checked())
+    }
+
+    /// Acquires the resource protected by the semaphore, blocking the current thread until the
+    /// resource is actually acquired or the semaphore is closed.
+    ///
+    /// Fails immediately if the resource would have to be waited for but the semaphore's wait
+    /// queue is full.
+    pub fn acquire_checked(&self) -> Result<(), AcquireError> {
+        self.acquire_checked_from(Instant::now())
+    }
+
+    /// Acquires a resource as `acquire_checked`, reporting the wait as starting at `started`.
+    fn acquire_checked_from(&self, started: Instant) -> Result<(), AcquireError> {
+        self.take_from_store(1)?;
+        let result = if self.fair {
+            self.acquire_queued(0)
+        } else {
+            self.acquire_unqueued()
+        };
+        let count = match result {
+            Ok(count) => count,
+            Err(err) => {
+                self.store.give(1);
+                return Err(err);
+            }
+        };
+        self.observe_acquire(1, started, count);
+        Ok(())
+    }
+
+    /// Takes `n` resources from the store, waiting for them to be released if they aren't all
+    /// available, or fails if the semaphore is closed while waiting.
+    ///
+    /// The store is only called with the state unlocked. A release through this semaphore gives
+    /// its resources to the store before bumping `store_generation` and waking waiters, so a
+    /// waiter which finds the generation unchanged since before its failed attempt can sleep
+    /// without missing that release.
+    fn take_from_store(&self, n: isize) -> Result<(), AcquireError> {
+        if self.store.try_take(n) {
+            return Ok(());
+        }
+        self.check_lock_order();
+        let mut waiting = false;
+        let result = loop {
+            let generation = self.lock().store_generation;
+            if self.store.try_take(n) {
+                break Ok(());
+            }
+            let mut state = self.lock();
+            if state.closed {
+                break Err(AcquireError::Closed);
+            }
+            if state.store_generation != generation {
+                continue;
+            }
+            if !waiting {
+                if let Err(err) = self.start_waiting(&mut state) {
+                    break Err(err);
+                }
+                if n > 1 {
+                    self.batch_waiters.fetch_add(1, Ordering::Relaxed);
+                }
+                waiting = true;
+            }
+            drop(self.wait_timeout(&self.condvar, state, Some(self.store_poll)));
+        };
+        if waiting {
+            let _state = self.lock();
+            if n > 1 {
+                self.batch_waiters.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.stop_waiting();
+        }
+        result
+    }
+
+    /// Blocks until a resource can be taken without joining the wait queue, or the semaphore is
+    /// closed.
+    fn acquire_unqueued(&self) -> Result<isize, AcquireError> {
+        self.check_lock_order();
+        let mut state = self.lock();
+        if state.count <= 0 && !state.closed {
+            self.start_waiting(&mut state)?;
+            if holds_only_resource(&state) {
+                self.stop_waiting();
+                drop(state);
+                reentrant_acquire();
+            }
+            while state.count <= 0 && !state.closed {
+                state = self.wait(&self.condvar, state);
+            }
+            self.stop_waiting();
+        }
+        if state.closed {
+            return Err(AcquireError::Closed);
+        }
+        Ok(self.take_held(&mut state, 1))
+    }
+
+    /// Joins the wait queue with the given priority and blocks until served or the semaphore is
+    /// closed.
+    fn acquire_queued(&self, priority: u32) -> Result<isize, AcquireError> {
+        self.check_lock_order();
+        let mut state = self.lock();
+        if state.closed {
+            return Err(AcquireError::Closed);
+        }
+        let ticket = state.queue.enqueue(priority);
+        self.wait_queued(state, ticket)
+    }
+
+    /// Blocks until the queued waiter with the given ticket is served or the semaphore is closed,
+    /// taking it out of the queue either way.
+    fn wait_queued(
+        &self,
+        mut state: MutexGuard<'_, State>,
+        ticket: u64,
+    ) -> Result<isize, AcquireError> {
+        let served = |state: &State| state.count > 0 && state.queue.head() == Some(ticket);
+        if !served(&state) {
+            if let Err(err) = self.start_waiting(&mut state) {
+                state.queue.remove(ticket);
+                return Err(err);
+            }
+            if holds_only_resource(&state) {
+                self.stop_waiting();
+                state.queue.remove(ticket);
+                drop(state);
+                reentrant_acquire();
+            }
+            while !served(&state) && !state.closed {
+                state = self.wait(&self.condvar, state);
+            }
+            self.stop_waiting();
+        }
+        state.queue.remove(ticket);
+        if state.closed {
+            return Err(AcquireError::Closed);
+        }
+        let count = self.take_held(&mut state, 1);
+
+        // Another waiter may be able to proceed now that we have left the queue.
+        if state.count > 0 {
+            self.notify(&mut state, 1);
+        }
+        Ok(count)
+    }
+
+    /// Acquires `n` resources at once, blocking the current thread until they are all available,
+    /// and returns an RAII guard which releases them when dropped.
+    ///
+    /// Fails with `AcquireError::Unsatisfiable` rather than blocking forever if `n` exceeds the
+    /// semaphore's capacity, including if the capacity is lowered below `n` while waiting, with
+    /// `AcquireError::Closed` if the semaphore is closed, and with `AcquireError::QueueFull` if it
+    /// would have to wait but its wait queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn acquire_checked_n(&self, n: isize) -> Result<SemaphoreGuard<'_, S>, AcquireError> {
+        assert!(n >= 0, "cannot acquire a negative number of resources");
+        self.check_lock_order();
+        let started = Instant::now();
+        if n > 0 {
+            self.take_from_store(n)?;
+        }
+        let mut state = self.lock();
+        let mut waiting = false;
+        let result = loop {
+            if state.closed {
+                break Err(AcquireError::Closed);
+            }
+            if state.capacity.is_some_and(|c| n > c) {
+                break Err(AcquireError::Unsatisfiable);
+            }
+            if n == 0 || self.can_take_unqueued(&state, n) {
+                break Ok(());
+            }
+            if !waiting {
+                if let Err(err) = self.start_waiting(&mut state) {
+                    break Err(err);
+                }
+                waiting = true;
+            }
+            self.batch_waiters.fetch_add(1, Ordering::Relaxed);
+            state = self.wait(&self.condvar, state);
+            self.batch_waiters.fetch_sub(1, Ordering::Relaxed);
+        };
+        if waiting {
+            self.stop_waiting();
+        }
+        if let Err(err) = result {
+            drop(state);
+            if n > 0 {
+                self.store.give(n);
+            }
+            return Err(err);
+        }
+        if n > 0 {
+            let count = self.take_held(&mut state, n);
+            drop(state);
+            self.observe_acquire(n, started, count);
+        }
+        Ok(SemaphoreGuard {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    /// Release a resource from the semaphore.
+    ///
+    /// Increments the semaphore's count and notifies any pending threads if necssary. The count
+    /// saturates at `isize::MAX` rather than overflowing.
+    #[inline]
+    pub fn release(&self) {
+        self.return_permits(1);
+        self.observe_release(1);
+    }
+
+    /// Release `n` resources to the semaphore at once.
+    ///
+    /// This is equivalent to calling `release` `n` times, but only takes the internal lock and
+    /// notifies pending threads once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn release_n(&self, n: isize) {
+        assert!(n >= 0, "cannot release a negative number of resources");
+        if n == 0 {
+            return;
+        }
+        self.return_permits(n);
+        self.observe_release(n);
+    }
+
+    /// Returns `n` resources held by the current thread to the semaphore, coalescing them with
+    /// concurrent releases if the semaphore was built to.
+    fn return_permits(&self, n: isize) {
+        self.note_released(n);
+        // The store gets them back before waiters are woken, so that they find them there.
+        self.store.give(n);
+        #[cfg(feature = "testing")]
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.apply_release(n);
+        #[cfg(feature = "testing")]
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Adds `n` released resources to the count, or leaves them to a concurrent release which
+    /// will add them if releases are coalesced.
+    fn apply_release(&self, n: isize) {
+        if !self.coalesce_releases {
+            let state = self.lock();
+            orphan(&state, n);
+            return self.give_and_unlock(state, n);
+        }
+
+        // Resources are only ever pending while the release which found none pending has yet to
+        // take them, so leaving them to that release never loses them.
+        if self.pending_releases.fetch_add(n, Ordering::AcqRel) != 0 {
+            return;
+        }
+        let state = self.lock();
+        let pending = self.pending_releases.swap(0, Ordering::AcqRel);
+        orphan(&state, pending);
+        self.give_and_unlock(state, pending);
+    }
+
+    /// Returns this semaphore's position in the global order that multiple semaphores are
+    /// acquired in: its creation order when lock order checking is enabled, so that the checks
+    /// agree with `acquire_all`, and otherwise its address. Semaphores sharing a place in the lock
+    /// order are ordered among themselves by address.
+    fn order_key(&self) -> (u64, usize) {
+        let address = self as *const Self as usize;
+        #[cfg(all(feature = "lock-order", debug_assertions))]
+        return (self.holds.order(), address);
+        #[cfg(not(all(feature = "lock-order", debug_assertions)))]
+        return (0, address);
+    }
+
+    /// Checks that the current thread may block on this semaphore without violating the global
+    /// lock order. This does nothing unless the `lock-order` feature is enabled in a debug build.
+    fn check_lock_order(&self) {
+        #[cfg(all(feature = "lock-order", debug_assertions))]
+        holds::check_order(&self.holds);
+    }
+
+    /// Returns whether no more threads may block waiting for a resource.
+    fn wait_queue_full(&self, state: &State) -> bool {
+        state
+            .max_waiters
+            .is_some_and(|max| self.waiters.load(Ordering::Relaxed) >= max)
+    }
+
+    /// Counts the current thread as blocked waiting for a resource, failing with
+    /// `AcquireError::QueueFull` if no more threads may wait.
+    ///
+    /// Every successful call must be paired with a `stop_waiting` under the same lock, once the
+    /// thread is done waiting.
+    fn start_waiting(&self, state: &mut State) -> Result<(), AcquireError> {
+        if self.wait_queue_full(state) {
+            return Err(AcquireError::QueueFull);
+        }
+        state.contended_acquires += 1;
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops counting the current thread as waiting, after a `start_waiting`.
+    fn stop_waiting(&self) {
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns whether a thread which is not in the wait queue may take `n` resources.
+    #[inline]
+    fn can_take_unqueued(&self, state: &State, n: isize) -> bool {
+        state.count >= n
+            && state.count > 0
+            && !state.closed
+            && (!self.fair || state.queue.is_empty())
+    }
+
+    /// Takes `n` resources from the state for the current thread to hold, which must have them
+    /// available, and returns the count left.
+    fn take_held(&self, state: &mut State, n: isize) -> isize {
+        self.take(state, n);
+        self.note_held(state, n);
+        state.count
+    }
+
+    /// Takes `n` resources from the state, which must have them available, without recording
+    /// that anyone holds them.
+    ///
+    /// Since the count is always at least `n` here the decrement cannot underflow, however
+    /// negative the semaphore was initialized.
+    fn take(&self, state: &mut State, n: isize) {
+        debug_assert!(state.count >= n);
+        state.count -= n;
+        if state.count == 0 {
+            if let Some(edges) = &self.edges {
+                (edges.on_exhausted)();
+            }
+        }
+        #[cfg(feature = "async")]
+        publish_count(state);
+    }
+
+    /// Records that the current thread now holds `n` more resources, for the checks on what
+    /// threads hold.
+    #[cfg_attr(
+        not(all(
+            debug_assertions,
+            any(feature = "lock-order", feature = "nested-holds")
+        )),
+        allow(unused_variables)
+    )]
+    fn note_held(&self, state: &mut State, n: isize) {
+        #[cfg(debug_assertions)]
+        if state.idle == 1 && state.track_holder {
+            state.holder = Some(crate::sync::current_thread_id());
+        }
+        #[cfg(any(
+            all(feature = "lock-order", debug_assertions),
+            feature = "nested-holds"
+        ))]
+        #[cfg_attr(not(feature = "nested-holds"), allow(unused_variables))]
+        let nested = holds::acquired(&self.holds, n);
+        #[cfg(feature = "nested-holds")]
+        if nested {
+            self.nested_hold.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the current thread no longer holds `n` of its resources, for the checks on
+    /// what threads hold.
+    #[cfg_attr(
+        not(any(
+            all(feature = "lock-order", debug_assertions),
+            feature = "nested-holds"
+        )),
+        allow(unused_variables)
+    )]
+    fn note_released(&self, n: isize) {
+        #[cfg(any(
+            all(feature = "lock-order", debug_assertions),
+            feature = "nested-holds"
+        ))]
+        holds::released(&self.holds, n);
+    }
+
+    /// Returns `n` resources to the state and wakes any threads that can now make progress.
+    ///
+    /// The count saturates at the capacity, or `isize::MAX` for uncapped semaphores, so releases
+    /// beyond that point are dropped rather than overflowing.
+    fn give(&self, state: &mut State, n: isize) {
+        let wakeup = self.give_deferred(state, n);
+        self.wake(wakeup);
+    }
+
+    /// Returns `n` resources to the state as `give`, then unlocks it.
+    ///
+    /// Blocked threads are notified before the lock is released unless the semaphore was built
+    /// with `notify_after_unlock`, in which case they are notified afterwards so that a woken
+    /// thread does not immediately block again on the lock still held by the releasing thread.
+    /// Either way no wakeup can be lost, since a thread only blocks on the condvar after checking
+    /// the count under the lock, and the count is updated before the lock is released.
+    fn give_and_unlock(&self, mut state: MutexGuard<'_, State>, n: isize) {
+        if self.notify_after_unlock {
+            let wakeup = self.give_deferred(&mut state, n);
+            drop(state);
+            self.wake(wakeup);
+        } else {
+            self.give(&mut state, n);
+        }
+    }
+
+    /// Returns `n` resources to the state as `give`, but leaves waking the threads blocked on the
+    /// condvar to the caller.
+    fn give_deferred(&self, state: &mut State, n: isize) -> Wakeup {
+        #[cfg(debug_assertions)]
+        {
+            state.holder = None;
+        }
+        state.store_generation = state.store_generation.wrapping_add(1);
+        let before = state.count;
+        let limit = state.capacity.unwrap_or(isize::MAX);
+        state.count = state.count.saturating_add(n).min(limit);
+        #[cfg(feature = "numa")]
+        if !state.queue.is_empty() {
+            state.queue.released_here();
+        }
+        if before <= 0 && state.count > 0 {
+            if let Some(edges) = &self.edges {
+                (edges.on_available)();
+            }
+        }
+        if state.count > before && state.count > 0 {
+            state.subscribers.retain(Subscriber::notify);
+        }
+        #[cfg(feature = "async")]
+        publish_count(state);
+        let wakeup = if state.closed {
+            Wakeup::None
+        } else {
+            self.notify_deferred(state, n)
+        };
+        self.notify_idle(state);
+        wakeup
+    }
+
+    /// Wakes the threads waiting for the semaphore to become idle, if it is.
+    ///
+    /// Threads only block on the idle condvar with the state locked, announcing themselves as
+    /// sleepers first, so the notification can be skipped while nobody sleeps. That saves a
+    /// system call on every release.
+    #[inline]
+    fn notify_idle(&self, state: &State) {
+        if state.count >= state.idle && self.sleepers.load(Ordering::SeqCst) > 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    /// Returns the current observer, which is cloned so that it can be invoked without holding
+    /// the observer lock.
+    #[inline]
+    fn observer(&self) -> Option<Arc<dyn SemaphoreObserver>> {
+        if !self.observed.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.observer.lock().unwrap().clone()
+    }
+
+    /// Reports an acquisition of `n` resources which started waiting at `started` and left `count`
+    /// resources available, as read while the state was still locked.
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    fn observe_acquire(&self, n: isize, started: Instant, count: isize) {
+        let waited = started.elapsed();
+        self.count_acquired(n);
+        self.record_wait(waited);
+        if let Some(observer) = self.observer() {
+            observer.on_acquire(n, waited);
+        }
+        #[cfg(feature = "otel")]
+        trace_acquire(waited, count);
+    }
+
+    /// Raises the longest wait observed to `waited`, if it is longer.
+    fn record_wait(&self, waited: Duration) {
+        let waited = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        let mut max = self
+            .max_wait_nanos
+            .load(std::sync::atomic::Ordering::Relaxed);
+        while waited > max {
+            match self.max_wait_nanos.compare_exchange_weak(
+                max,
+                waited,
+                std::sync::atomic::Ordering::Relaxed,
+                std::sync::atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => max = actual,
+            }
+        }
+    }
+
+    /// Reports a release of `n` resources.
+    fn observe_release(&self, n: isize) {
+        self.count_released(n);
+        if let Some(observer) = self.observer() {
+            observer.on_release(n);
+        }
+    }
+
+    /// Adds `n` resources to the exported total acquired, if the semaphore has metrics.
+    #[inline]
+    fn count_acquired(&self, n: isize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.acquired.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds `n` resources to the exported total released, if the semaphore has metrics.
+    #[inline]
+    fn count_released(&self, n: isize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.released.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Wakes the threads which could make progress now that `released` resources have been
+    /// returned to the state.
+    ///
+    /// Only the waiter at the head of the priority queue may proceed, and the condvar cannot
+    /// target it, so all threads are woken whenever priority waiters are present. Likewise when
+    /// several resources are released at once, since each may satisfy a different waiter.
+    fn notify(&self, state: &mut State, released: isize) {
+        let wakeup = self.notify_deferred(state, released);
+        self.wake(wakeup);
+    }
+
+    /// Wakes pending futures as `notify`, and returns which threads blocked on the condvar need
+    /// waking.
+    #[cfg_attr(not(feature = "async"), allow(unused_variables))]
+    fn notify_deferred(&self, state: &mut State, released: isize) -> Wakeup {
+        self.grant_pending(state);
+        // Parked threads are woken for the same reason as futures.
+        unpark_all(state);
+        // Pending futures re-register when they are polled again, so every one of them is woken
+        // rather than risking a wakeup being lost to a future which is dropped before it runs.
+        #[cfg(feature = "async")]
+        {
+            for (_, waker) in state.wakers.drain(..) {
+                waker.wake();
+            }
+            self.wakers_changed(state);
+        }
+
+        let single_waiters = state.queue.is_empty()
+            && state.watchers == 0
+            && self.batch_waiters.load(Ordering::Relaxed) == 0;
+        if !single_waiters || (released > 1 && !self.exact_wakeups) {
+            return Wakeup::All;
+        }
+        // Each waiter takes one resource, so waking more threads than resources were released
+        // only makes the losers sleep again. Every thread blocked on a condvar is counted as a
+        // sleeper, so there is no point notifying more than that either.
+        let sleepers = self.sleepers.load(Ordering::SeqCst);
+        match (released.max(0) as usize).min(sleepers) {
+            0 => Wakeup::None,
+            n => Wakeup::Some(n),
+        }
+    }
+
+    /// Hands resources to the pending acquisitions at the head of the wait queue, which have no
+    /// thread waiting to take them, so that they don't hold up the waiters behind them.
+    fn grant_pending(&self, state: &mut State) {
+        if state.pending.is_empty() {
+            return;
+        }
+        while state.count > 0 && !state.closed {
+            let head = match state.queue.head() {
+                Some(head) => head,
+                None => return,
+            };
+            match state.pending.iter_mut().find(|(ticket, _)| *ticket == head) {
+                Some((_, granted)) => *granted = true,
+                None => return,
+            }
+            state.queue.remove(head);
+            self.take(state, 1);
+        }
+    }
+
+    /// Wakes the given threads blocked on the condvar.
+    fn wake(&self, wakeup: Wakeup) {
+        match wakeup {
+            Wakeup::None => {}
+            Wakeup::Some(n) => {
+                for _ in 0..n {
+                    self.condvar.notify_one();
+                }
+            }
+            Wakeup::All => self.condvar.notify_all(),
+        }
+    }
+
+    /// Attempts to acquire a resource from the semaphore without waiting for one to become
+    /// available.
+    ///
+    /// Returns `true` if a resource was acquired. Note that this still takes the internal lock,
+    /// so it may briefly block if another thread is currently operating on the semaphore.
+    #[inline]
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_n(1)
+    }
+
+    /// Acquires `n` resources without waiting if they are all available, returning whether they
+    /// were acquired.
+    pub(crate) fn try_acquire_n(&self, n: isize) -> bool {
+        let started = Instant::now();
+        if n <= 0 {
+            return n == 0;
+        }
+        if !self.store.try_take(n) {
+            return false;
+        }
+        let mut state = self.lock();
+        if !self.can_take_unqueued(&state, n) {
+            drop(state);
+            self.store.give(n);
+            return false;
+        }
+        let count = self.take_held(&mut state, n);
+        drop(state);
+        self.observe_acquire(n, started, count);
+        true
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard to release the semaphore
+    /// when the guard is dropped.
+    ///
+    /// This function is semantically equivalent to an `acquire` followed by a `release` when the
+    /// returned guard is dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_, S> {
+        self.acquire();
+        SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning an owned RAII guard to release the
+    /// semaphore when the guard is dropped.
+    pub fn access_owned(self: &Arc<Self>) -> OwnedSemaphoreGuard<S> {
+        self.acquire();
+        OwnedSemaphoreGuard {
+            sem: self.clone(),
+            permits: 1,
+        }
+    }
+
+    /// Attempts to acquire a resource of this semaphore without waiting, returning an owned RAII
+    /// guard if one was available.
+    pub fn try_access_owned(self: &Arc<Self>) -> Option<OwnedSemaphoreGuard<S>> {
+        if self.try_acquire() {
+            Some(OwnedSemaphoreGuard {
+                sem: self.clone(),
+                permits: 1,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire a resource of this semaphore without waiting, returning an RAII
+    /// guard if one was available.
+    #[inline]
+    pub fn try_access(&self) -> Option<SemaphoreGuard<'_, S>> {
+        if self.try_acquire() {
+            Some(SemaphoreGuard {
+                sem: self,
+                permits: 1,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Locks the state, first adding any resources released by `fast_release` to the count.
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().unwrap();
+        self.flush_fast_releases(&mut state);
+        state
+    }
+
+    /// Adds any resources released by `fast_release` to the count, waking threads as `release`
+    /// would have.
+    #[inline]
+    fn flush_fast_releases(&self, state: &mut State) {
+        if self.fast_releases.load(Ordering::SeqCst) != 0 {
+            let n = self.fast_releases.swap(0, Ordering::SeqCst);
+            self.give(state, n);
+        }
+    }
+
+    /// Blocks on `condvar` until notified, as `Condvar::wait`.
+    fn wait<'a>(&self, condvar: &Condvar, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.wait_timeout(condvar, state, None)
+    }
+
+    /// Blocks on `condvar` until notified or until the timeout, if any, elapses.
+    ///
+    /// The wait is announced to `fast_release` first, which only locks the state to wake threads
+    /// when it sees one waiting. A fast release made after the caller last checked the state
+    /// therefore either sees this thread waiting and wakes it, or is seen by the check below, in
+    /// which case the thread returns straight away, as on a spurious wakeup.
+    fn wait_timeout<'a>(
+        &self,
+        condvar: &Condvar,
+        mut state: MutexGuard<'a, State>,
+        timeout: Option<Duration>,
+    ) -> MutexGuard<'a, State> {
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        crate::sync::store_load_fence();
+        if self.fast_releases.load(Ordering::SeqCst) == 0 {
+            state = match timeout {
+                Some(timeout) => condvar.wait_timeout(state, timeout).unwrap().0,
+                None => condvar.wait(state).unwrap(),
+            };
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        self.flush_fast_releases(&mut state);
+        state
+    }
+
+    /// Records the number of registered async wakers for `fast_release`, after they change.
+    #[cfg(feature = "async")]
+    fn wakers_changed(&self, state: &State) {
+        self.registered_wakers
+            .store(state.wakers.len(), Ordering::SeqCst);
+    }
+
+    /// Returns whether any thread or future is waiting on the semaphore.
+    fn has_sleepers(&self) -> bool {
+        #[cfg(feature = "async")]
+        if self.registered_wakers.load(Ordering::SeqCst) > 0 {
+            return true;
+        }
+        self.sleepers.load(Ordering::SeqCst) > 0
+    }
+
+    /// Test hook which holds the internal lock, so tests can exercise contention on the mutex
+    /// itself.
+    #[cfg(test)]
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.lock()
+    }
+}
+
+impl Semaphore {
+    /// Initialize a new semaphore with the initial count specified.
+    ///
+    /// The count can be thought of as the number of resources that the semaphore is protecting.
+    /// A call to `acquire` or `access` will block until at least one resource is available. It is
+    /// valid to initialize a semaphore with a negative count.
+    pub fn new(n: isize) -> Self {
+        Semaphore::with_priority_aging(n, 0.0)
+    }
+
+    /// Initialize a new semaphore protecting `n` resources.
+    ///
+    /// This is `new` for the common case of a plain resource count, taking the unsigned type
+    /// such counts usually come as. Use `new` for the advanced cases needing a negative initial
+    /// count.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than `isize::MAX`, the largest count a semaphore can hold.
+    pub fn new_capacity(n: usize) -> Self {
+        match isize::try_from(n) {
+            Ok(n) => Semaphore::new(n),
+            Err(_) => panic!("semaphore capacity {} exceeds isize::MAX", n),
+        }
+    }
+
+    /// Initialize a new semaphore with one resource per CPU the process may run on, as reported by
+    /// `std::thread::available_parallelism`, or a single resource if that can't be determined.
+    pub fn sized_to_cpus() -> Self {
+        Semaphore::new(available_cpus())
+    }
+
+    /// Initialize a new semaphore with `frac` resources per CPU the process may run on, as
+    /// `sized_to_cpus`.
+    ///
+    /// The count is rounded down, but is always at least one, so that the semaphore can be
+    /// acquired at all; a `frac` above one oversubscribes the CPUs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frac` is not a positive, finite number.
+    pub fn sized_to(frac: f64) -> Self {
+        assert!(
+            frac.is_finite() && frac > 0.0,
+            "fraction of CPUs must be positive and finite"
+        );
+        // The cast saturates, so huge fractions can't overflow the count.
+        let n = (available_cpus() as f64 * frac).floor() as isize;
+        Semaphore::new(n.max(1))
+    }
+
+    /// Returns a builder for a semaphore with the initial count specified, for combining options
+    /// which have no dedicated constructor.
+    pub fn builder(n: isize) -> SemaphoreBuilder {
+        SemaphoreBuilder {
+            count: n,
+            ordering: WaiterOrder::Unordered,
+            max: None,
+            min: 0,
+            max_waiters: None,
+            aging_rate: 0.0,
+            notify_after_unlock: false,
+            coalesce_releases: false,
+            exact_wakeups: true,
+            detect_reentrant_acquire: true,
+            #[cfg(feature = "numa")]
+            prefer_local_waiters: false,
+        }
+    }
+
+    /// Turns off the debug check for a thread re-acquiring the single resource it holds, as
+    /// `SemaphoreBuilder::detect_reentrant_acquire(false)`, for semaphores whose resources are
+    /// released by other threads than the ones which acquired them.
+    pub fn for_signalling(self) -> Self {
+        #[cfg(debug_assertions)]
+        {
+            let mut sem = self;
+            sem.state.get_mut().unwrap().track_holder = false;
+            sem
+        }
+        #[cfg(not(debug_assertions))]
+        self
+    }
+
+    /// Gives this semaphore the same place in the lock order as `other`, for semaphores dividing
+    /// one budget between them which may be held together in any order.
+    #[cfg_attr(
+        not(all(feature = "lock-order", debug_assertions)),
+        allow(unused_variables)
+    )]
+    pub(crate) fn share_order(&mut self, other: &Semaphore) {
+        #[cfg(all(feature = "lock-order", debug_assertions))]
+        self.holds.share_order(&other.holds);
+    }
+
+    /// Initialize a new semaphore whose priority waiters age at the given rate.
+    ///
+    /// A thread waiting in `acquire_priority` gains `rate` priority levels for every second it
+    /// spends waiting, which guarantees that low priority waiters are eventually served ahead of
+    /// newly arrived high priority ones.
+    pub fn with_priority_aging(n: isize, rate: f64) -> Self {
+        Semaphore::from_store(InMemory, n, rate, Duration::ZERO)
+    }
+
+    /// Initialize a new fair semaphore with the initial count specified.
+    ///
+    /// Every acquisition on a fair semaphore goes through the wait queue, so waiting threads are
+    /// served in arrival order (or priority order, for `acquire_priority`) and a newly arriving
+    /// thread can never take a resource ahead of one that is already waiting. This includes
+    /// `try_acquire`, which fails while any thread is queued.
+    pub fn fair(n: isize) -> Self {
+        Semaphore {
+            fair: true,
+            ..Semaphore::new(n)
+        }
+    }
+
+    /// Initialize a new semaphore whose count can never exceed `max`.
+    ///
+    /// Releases which would take the count above `max` are dropped, which protects against
+    /// accidentally releasing more resources than the semaphore was created with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than `max`.
+    pub fn with_max(n: isize, max: isize) -> Self {
+        assert!(n <= max, "initial count exceeds the maximum");
+        let mut sem = Semaphore::new(n);
+        let state = sem.state.get_mut().unwrap();
+        state.capacity = Some(max);
+        state.idle = max;
+        sem
+    }
+
+    /// Initialize a new semaphore which can never be shrunk below `min` resources in total.
+    ///
+    /// `forget` and `shrink_to` stop at the floor rather than removing resources below it, which
+    /// guards against shrinking the semaphore so far that the threads relying on it deadlock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is less than `min`, or `min` is negative.
+    pub fn with_min(n: isize, min: isize) -> Self {
+        assert!(min >= 0, "minimum must not be negative");
+        assert!(n >= min, "initial count is below the minimum");
+        let mut sem = Semaphore::new(n);
+        sem.state.get_mut().unwrap().min = min;
+        sem
+    }
+
+    /// Initialize a new semaphore which allows at most `max_waiters` threads to block waiting for
+    /// a resource at once.
+    ///
+    /// Once that many threads are blocked waiting, further acquisitions which would have to wait
+    /// fail instead, with `AcquireError::QueueFull` where they report why, which bounds the
+    /// backlog an overloaded semaphore can build up.
+    pub fn with_max_waiters(n: isize, max_waiters: usize) -> Self {
+        let mut sem = Semaphore::new(n);
+        sem.state.get_mut().unwrap().max_waiters = Some(max_waiters);
+        sem
+    }
+
+    /// Initialize a new semaphore which reports its events to `observer`.
+    pub fn with_observer(n: isize, observer: Arc<dyn SemaphoreObserver>) -> Self {
+        Semaphore {
+            observer: Mutex::new(Some(observer)),
+            observed: AtomicBool::new(true),
+            ..Semaphore::new(n)
+        }
+    }
+
+    /// Initialize a new semaphore which counts its acquisitions and releases, for exporting with
+    /// `render_prometheus` under metric names prefixed with `name`.
+    pub fn with_metrics_name(n: isize, name: &str) -> Self {
+        Semaphore {
+            metrics: Some(Metrics {
+                name: name.to_string(),
+                acquired: AtomicU64::new(0),
+                released: AtomicU64::new(0),
+            }),
+            ..Semaphore::new(n)
+        }
+    }
+
+    /// Returns the process-wide semaphore registered under `name`, creating it with `n` resources
+    /// if this is the first call for that name.
+    ///
+    /// Every later call with the same name returns the same semaphore, whatever `n` it passes, so
+    /// modules which can't share an `Arc` can still coordinate through one semaphore, much like
+    /// named semaphores shared between processes. Registered semaphores live for the rest of the
+    /// process.
+    pub fn named(name: &str, n: isize) -> Arc<Semaphore> {
+        let mut named = NAMED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        named
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(n)))
+            .clone()
+    }
+
+    /// Initialize a new semaphore which invokes callbacks when its count crosses zero.
+    ///
+    /// `on_exhausted` is invoked when an acquisition takes the last available resource, and
+    /// `on_available` when a release makes a resource available again after the semaphore was
+    /// exhausted. The callbacks fire exactly once per transition rather than on every operation.
+    /// They are invoked while the semaphore's internal lock is held, so that transitions are
+    /// reported in the order they happen; they must not call back into the semaphore.
+    pub fn with_edge_callbacks<E, A>(n: isize, on_exhausted: E, on_available: A) -> Self
+    where
+        E: Fn() + Send + Sync + 'static,
+        A: Fn() + Send + Sync + 'static,
+    {
+        Semaphore {
+            edges: Some(EdgeCallbacks {
+                on_exhausted: Box::new(on_exhausted),
+                on_available: Box::new(on_available),
+            }),
+            ..Semaphore::new(n)
+        }
+    }
+
+    /// Acquires a resource as `acquire`, but reports the acquisition to the observer as having
+    /// waited since `enqueued_at` rather than since this call.
+    ///
+    /// This accounts for time the caller spent waiting before reaching the semaphore, such as in a
+    /// request queue, so the observer sees the full latency from enqueueing to being granted a
+    /// resource.
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as `acquire`.
+    pub fn acquire_from(&self, enqueued_at: Instant) {
+        unwrap_acquired(self.acquire_checked_from(enqueued_at))
+    }
+
+    /// Acquires the resource protected by the semaphore, as `acquire`, backing off as given after
+    /// every wakeup before retrying.
+    ///
+    /// Jittered backoff is useful when many threads are woken at once to contend for a few
+    /// resources, which would otherwise stampede the internal lock. The jitter comes from a cheap
+    /// per-call pseudo-random sequence, not a cryptographic source.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is closed, either before or while waiting, or if its wait queue is
+    /// full.
+    pub fn acquire_with(&self, backoff: Backoff) {
+        let (min, max) = match backoff {
+            Backoff::None => return self.acquire(),
+            Backoff::Jitter { min, max } => (min, max.max(min)),
+        };
+        self.check_lock_order();
+        let started = Instant::now();
+        let mut jitter = Jitter::new(min, max);
+        let mut state = self.lock();
+        let mut waiting = false;
+        loop {
+            if state.closed || self.can_take_unqueued(&state, 1) {
+                if waiting {
+                    self.stop_waiting();
+                }
+                break;
+            }
+            if !waiting {
+                if self.start_waiting(&mut state).is_err() {
+                    drop(state);
+                    panic!("acquired from a semaphore with a full wait queue");
+                }
+                waiting = true;
+            }
+            state = self.wait(&self.condvar, state);
+
+            // The count is re-checked once the lock is taken again, so a resource released while
+            // backing off is not missed.
+            drop(state);
+            jitter.wait();
+            state = self.lock();
+        }
+        if state.closed {
+            drop(state);
+            panic!("acquired from a closed semaphore");
+        }
+        let count = self.take_held(&mut state, 1);
+        drop(state);
+        self.observe_acquire(1, started, count);
+    }
+
+    /// Allocates room for `expected_waiters` threads in the wait queue up front, so that a burst of
+    /// that many waiters doesn't pay for growing it while they contend for the semaphore.
+    ///
+    /// The wait queue holds the waiters of a fair semaphore and priority waiters. The lock and
+    /// condvars never allocate, so there is nothing to warm up for them.
+    pub fn prewarm(&self, expected_waiters: usize) {
+        self.lock().queue.reserve(expected_waiters);
+    }
+
+    /// Acquires a resource, blocking until one is available unless the thread is unparked first,
+    /// and returns whether it was acquired.
+    ///
+    /// The thread waits by parking rather than on the condvar, so a `Thread::unpark` from another
+    /// thread aborts the acquisition and returns `false` without taking a resource, which lets
+    /// park-based cancellation schemes cancel it. An unpark token left over from before the call,
+    /// or a spurious wakeup from `thread::park`, aborts it the same way. Also returns `false` if
+    /// the semaphore is closed or its wait queue is full.
+    pub fn acquire_parkable(&self) -> bool {
+        self.acquire_parked(|| false, true)
+    }
+
+    /// Acquires a resource, blocking until one is available unless `scope` is cancelled first,
+    /// and returns whether it was acquired.
+    ///
+    /// Cancelling the scope or any of its ancestors while the thread waits wakes it straight away,
+    /// and the acquisition returns `false` without taking a resource, so nested operations can
+    /// unwind as soon as the outermost of them is cancelled. Also returns `false` if the scope is
+    /// already cancelled, the semaphore is closed or its wait queue is full.
+    pub fn acquire_in_scope(&self, scope: &CancelScope) -> bool {
+        let _registration = scope.register();
+        self.acquire_parked(|| scope.is_cancelled(), false)
+    }
+
+    /// Acquires a resource, waiting by parking the thread, unless `cancelled` returns `true` first
+    /// or, if `unpark_cancels` is set, the thread is unparked by anything but a release.
+    fn acquire_parked<F>(&self, cancelled: F, unpark_cancels: bool) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        let started = Instant::now();
+        self.check_lock_order();
+        let mut state = self.lock();
+        let mut waiting = false;
+        let acquired = loop {
+            if state.closed {
+                break None;
+            }
+            if cancelled() {
+                // We may have been woken for a resource we are now leaving, so pass it on.
+                if state.count > 0 {
+                    self.notify(&mut state, 1);
+                }
+                break None;
+            }
+            if self.can_take_unqueued(&state, 1) {
+                break Some(self.take_held(&mut state, 1));
+            }
+            if !waiting {
+                if self.start_waiting(&mut state).is_err() {
+                    break None;
+                }
+                waiting = true;
+            }
+
+            // As in `wait_timeout`, a fast release racing with parking is either seen here or
+            // sees this thread waiting, and then locks the state only once it is registered.
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            crate::sync::store_load_fence();
+            if self.fast_releases.load(Ordering::SeqCst) != 0 {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                self.flush_fast_releases(&mut state);
+                continue;
+            }
+            let id = state.next_parked;
+            state.next_parked += 1;
+            state.parked.push((id, thread::current()));
+            drop(state);
+            thread::park();
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+
+            // A release unparks the thread by removing its registration, so finding it still
+            // registered means something else unparked it.
+            state = self.lock();
+            if let Some(i) = state.parked.iter().position(|&(other, _)| other == id) {
+                state.parked.remove(i);
+                if unpark_cancels {
+                    break None;
+                }
+            }
+        };
+        if waiting {
+            self.stop_waiting();
+        }
+        drop(state);
+        if let Some(count) = acquired {
+            self.observe_acquire(1, started, count);
+        }
+        acquired.is_some()
+    }
+
+    /// Acquires a resource, first spinning for up to `spins` attempts and then blocking for up to
+    /// `timeout`, and returns whether it was acquired.
+    ///
+    /// Spinning avoids the cost of parking the thread when a resource is about to be released,
+    /// while the timeout bounds how long the caller can be held up in total. Returns `false` if
+    /// the timeout elapses, the semaphore is closed or its wait queue is full.
+    pub fn acquire_spin_then_timeout(&self, spins: usize, timeout: Duration) -> bool {
+        for _ in 0..spins {
+            if self.try_acquire() {
+                return true;
+            }
+            std::hint::spin_loop();
+        }
+
+        self.check_lock_order();
+        let started = Instant::now();
+        let deadline = started + timeout;
+        let mut state = self.lock();
+        let mut waiting = false;
+        let acquired = loop {
+            if state.closed {
+                break None;
+            }
+            if self.can_take_unqueued(&state, 1) {
+                break Some(self.take_held(&mut state, 1));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break None;
+            }
+            if !waiting {
+                if self.start_waiting(&mut state).is_err() {
+                    break None;
+                }
+                waiting = true;
+            }
+            state = self.wait_timeout(&self.condvar, state, Some(deadline - now));
+        };
+        if waiting {
+            self.stop_waiting();
+        }
+        drop(state);
+        if let Some(count) = acquired {
+            self.observe_acquire(1, started, count);
+        }
+        acquired.is_some()
+    }
+
+    /// Acquires the resource protected by the semaphore with the given priority, blocking the
+    /// current thread until the resource is actually acquired.
+    ///
+    /// Threads waiting in `acquire_priority` are served highest priority first, and in arrival
+    /// order among equal priorities. Unless the semaphore is fair, priorities are only honored
+    /// among priority waiters and a plain `acquire` may still take a resource ahead of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is closed, either before or while waiting, or if its wait queue is
+    /// full.
+    pub fn acquire_priority(&self, priority: u32) {
+        let started = Instant::now();
+        let count = unwrap_acquired(self.acquire_queued(priority));
+        self.observe_acquire(1, started, count);
+    }
+
+    /// Reserves `n` resources from this semaphore, blocking until they are all available, and
+    /// returns a child semaphore handing them out.
+    ///
+    /// This models nested concurrency limits: the child limits its own users to `n` concurrent
+    /// acquisitions, all of which also count against this semaphore.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than this semaphore's capacity, or if the semaphore is
+    /// closed.
+    pub fn fork(&self, n: isize) -> ChildSemaphore<'_> {
+        let reservation = match self.acquire_checked_n(n) {
+            Ok(reservation) => reservation,
+            Err(e) => panic!("failed to fork semaphore: {}", e),
+        };
+        ChildSemaphore {
+            sem: Semaphore::with_max(n, n),
+            reservation,
+        }
+    }
+
+    /// Reserves `n` resources from this semaphore without waiting, returning a child semaphore
+    /// handing them out if they were all available.
+    pub fn try_fork(&self, n: isize) -> Option<ChildSemaphore<'_>> {
+        if !self.try_acquire_n(n) {
+            return None;
+        }
+        Some(ChildSemaphore {
+            sem: Semaphore::with_max(n, n),
+            reservation: SemaphoreGuard {
+                sem: self,
+                permits: n,
+            },
+        })
+    }
+
+    /// Runs `f` with a scope whose guards are all released by the time this returns.
+    ///
+    /// Guards acquired through the scope are tied to its lifetime, so they can't escape `f`. Any
+    /// still held when `f` returns or panics, such as ones passed to `std::mem::forget`, are
+    /// released then, so no control flow inside the scope can leak a resource.
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope SemaphoreScope<'scope, 'env>) -> T,
+    {
+        let scope = SemaphoreScope {
+            sem: self,
+            held: AtomicIsize::new(0),
+            scope: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        let leaked = scope.held.load(Ordering::SeqCst);
+        if leaked > 0 {
+            self.release_n(leaked);
+        }
+        match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Acquires as many resources as `cost` asks for, blocking the current thread until they are
+    /// all available, and returns the number acquired.
+    ///
+    /// `cost` is evaluated under the internal lock once per attempt: on entry, and again each
+    /// time the thread wakes up to retry. An attempt only succeeds if every resource it asked for
+    /// is available, and the resources taken are exactly those asked for by the successful
+    /// attempt, so a cost which changes between attempts is always honored consistently.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cost` returns a negative number, if the semaphore is closed, or if the
+    /// resources would have to be waited for but the semaphore's wait queue is full.
+    pub fn acquire_dynamic<F>(&self, cost: F) -> isize
+    where
+        F: Fn() -> isize,
+    {
+        self.check_lock_order();
+        let started = Instant::now();
+        let mut state = self.lock();
+        let mut waiting = false;
+        loop {
+            if state.closed {
+                if waiting {
+                    self.stop_waiting();
+                }
+                drop(state);
+                panic!("acquired from a closed semaphore");
+            }
+            let n = cost();
+            if n <= 0 || self.can_take_unqueued(&state, n) {
+                if waiting {
+                    self.stop_waiting();
+                }
+                if n < 0 {
+                    drop(state);
+                    panic!("cannot acquire a negative number of resources");
+                }
+                if n > 0 {
+                    let count = self.take_held(&mut state, n);
+                    drop(state);
+                    self.observe_acquire(n, started, count);
+                }
+                return n;
+            }
+            if !waiting {
+                if self.start_waiting(&mut state).is_err() {
+                    drop(state);
+                    panic!("acquired from a semaphore with a full wait queue");
+                }
+                waiting = true;
+            }
+            self.batch_waiters.fetch_add(1, Ordering::Relaxed);
+            state = self.wait(&self.condvar, state);
+            self.batch_waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Closes the semaphore.
+    ///
+    /// Every thread waiting on the semaphore is woken and its acquisition fails, as do all later
+    /// acquisitions. Resources released after the semaphore is closed, for instance by dropping
+    /// guards which were held when it was closed, are still added to the count so that
+    /// `available_permits` keeps accounting for them, but they never wake anyone since no
+    /// acquisition can succeed.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        self.close_locked(&mut state);
+    }
+
+    /// Closes the semaphore as `close`, with the state already locked.
+    fn close_locked(&self, state: &mut State) {
+        state.closed = true;
+        self.condvar.notify_all();
+        unpark_all(state);
+        for (_, handoff) in &mut state.handoffs {
+            if let Some(waiter) = handoff.waiter.take() {
+                waiter.unpark();
+            }
+        }
+        #[cfg(feature = "async")]
+        {
+            for (_, waker) in state.wakers.drain(..) {
+                waker.wake();
+            }
+            self.wakers_changed(state);
+        }
+    }
+
+    /// Closes the semaphore, as `close`, and invokes `on_orphan` once for every resource its
+    /// holders release afterwards, through `release`, `release_n` or dropping a guard.
+    ///
+    /// Resources still held when a semaphore is closed can never be handed out again, so this lets
+    /// the underlying resources be torn down as their holders give them back. Resources added to
+    /// the semaphore in other ways, as by `add_permits`, aren't orphans and are only counted. The
+    /// callback is invoked while the semaphore's internal lock is held, so it must not call back
+    /// into the semaphore.
+    pub fn close_with<F>(&self, on_orphan: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let mut state = self.lock();
+        state.on_orphan = Some(Box::new(on_orphan));
+        self.close_locked(&mut state);
+    }
+
+    /// Returns whether the semaphore has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Returns whether the semaphore's internal lock is poisoned, because a thread panicked while
+    /// holding it.
+    ///
+    /// Operations on a poisoned semaphore panic until the poison is cleared by `clear_poison`.
+    #[cfg(not(loom))]
+    pub fn is_poisoned(&self) -> bool {
+        self.state.is_poisoned()
+    }
+
+    /// Clears the poison from the semaphore's internal lock, so that operations on it succeed
+    /// again.
+    ///
+    /// The panic which poisoned the lock may have left the count inconsistent with the resources
+    /// actually held, so this is only safe once the caller has dealt with the aftermath, for
+    /// instance by checking `available_permits` against the holders it knows of.
+    #[cfg(not(loom))]
+    pub fn clear_poison(&self) {
+        self.state.clear_poison();
+    }
+
+    /// Registers the current thread as a consumer which a producer can hand a resource to
+    /// directly with `release_to`, rather than to whichever waiter wins it.
+    pub fn register_handoff(&self) -> HandoffToken<'_> {
+        let mut state = self.lock();
+        let id = state.next_handoff;
+        state.next_handoff += 1;
+        state.handoffs.push((
+            id,
+            Handoff {
+                granted: false,
+                waiter: None,
+            },
+        ));
+        HandoffToken { sem: self, id }
+    }
+
+    /// Releases a resource held by the current thread straight to the consumer registered with
+    /// `ticket`, waking only that consumer.
+    ///
+    /// Falls back to an ordinary `release` if the consumer has given up its registration, has
+    /// already been handed a resource it has yet to take, or the semaphore is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ticket` was registered with a different semaphore.
+    pub fn release_to(&self, ticket: HandoffTicket<'_>) {
+        assert!(
+            std::ptr::eq(self, ticket.sem),
+            "resource handed to a consumer of a different semaphore"
+        );
+        let mut state = self.lock();
+        let closed = state.closed;
+        let handoff = state
+            .handoffs
+            .iter_mut()
+            .find(|(id, _)| *id == ticket.id)
+            .map(|(_, handoff)| handoff);
+        match handoff {
+            Some(handoff) if !handoff.granted && !closed => {
+                handoff.granted = true;
+                if let Some(waiter) = handoff.waiter.take() {
+                    waiter.unpark();
+                }
+                #[cfg(debug_assertions)]
+                {
+                    state.holder = None;
+                }
+                drop(state);
+                self.note_released(1);
+                self.observe_release(1);
+            }
+            _ => {
+                drop(state);
+                self.release();
+            }
+        }
+    }
+
+    /// Release a resource from the semaphore without taking the internal lock unless a thread is
+    /// waiting.
+    ///
+    /// The resource is added to an atomic counter, and only added to the count the next time the
+    /// semaphore is locked by any operation, while the lock is only taken here to wake a thread
+    /// if one is waiting. This is intended for a single producer releasing to many consumers,
+    /// where the producer's releases would otherwise mostly be uncontended lock round trips;
+    /// consumers use the ordinary acquisitions. It is only safe as a replacement for `release` in
+    /// that pattern: released resources are not reported to the observer, and edge callbacks and
+    /// subscribers only learn of them once they are added to the count.
+    pub fn fast_release(&self) {
+        self.note_released(1);
+        self.fast_releases.fetch_add(1, Ordering::SeqCst);
+        self.count_released(1);
+        crate::sync::store_load_fence();
+        if self.has_sleepers() {
+            // Locking adds the resource to the count and wakes a waiter for it.
+            drop(self.lock());
+        }
+    }
+
+    /// Permanently adds `n` resources to the semaphore, raising its capacity if it has one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn add_permits(&self, n: isize) {
+        assert!(n >= 0, "cannot add a negative number of resources");
+        let mut state = self.lock();
+        state.capacity = state.capacity.map(|c| c.saturating_add(n));
+        state.idle = state.idle.saturating_add(n);
+        self.give(&mut state, n);
+    }
+
+    /// Permanently adds the resources of `other` to this semaphore, as `add_permits`, waking any
+    /// threads they satisfy.
+    ///
+    /// This combines two pools into one. Taking `other` by value guarantees that no guards on it
+    /// are outstanding, so none of its resources can be released into it after the merge; only
+    /// its available resources are added. A negative count on `other` adds nothing.
+    pub fn merge(&self, other: Semaphore) {
+        self.add_permits(other.into_inner().max(0));
+    }
+
+    /// Permanently removes up to `n` currently available resources from the semaphore, lowering
+    /// its capacity if it has one, and returns the number removed.
+    ///
+    /// Resources which are currently held are never revoked, so fewer than `n` resources are
+    /// removed if fewer are available. Nor are resources removed below the semaphore's minimum, as
+    /// `with_min`; the number removed is clamped to stay above it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative.
+    pub fn forget(&self, n: isize) -> isize {
+        assert!(n >= 0, "cannot forget a negative number of resources");
+        let mut state = self.lock();
+        let forgotten = n.min(state.count).min(state.idle - state.min).max(0);
+        if forgotten > 0 {
+            self.take(&mut state, forgotten);
+            state.capacity = state.capacity.map(|c| c - forgotten);
+            state.idle -= forgotten;
+        }
+        forgotten
+    }
+
+    /// Moves `n` available resources from this semaphore to `to`, without anyone holding them in
+    /// between. Returns `false`, and moves nothing, if fewer than `n` are available.
+    pub(crate) fn transfer(&self, to: &Semaphore, n: isize) -> bool {
+        let mut state = self.lock();
+        if n > 0 && !self.can_take_unqueued(&state, n) {
+            return false;
+        }
+        self.take(&mut state, n);
+        drop(state);
+        to.give_and_unlock(to.lock(), n);
+        true
+    }
+
+    /// Permanently removes `n` held resources, which will never be released, from the semaphore's
+    /// total.
+    fn discard(&self, n: isize) {
+        // The resources are no longer held, for the purposes of the checks on what threads hold.
+        self.note_released(n);
+        let mut state = self.lock();
+        state.capacity = state.capacity.map(|c| c - n);
+        state.idle -= n;
+        self.notify_idle(&state);
+    }
+
+    /// Shrinks the semaphore to `target` resources, lowering its capacity if it has one, and
+    /// blocks until it has settled there.
+    ///
+    /// Resources which are currently held are never revoked. Available resources are reclaimed
+    /// straight away, and the rest as they are released, until the semaphore holds `target`
+    /// resources in total; this then blocks until they are all available, as `wait_for_idle`, so
+    /// that no more than `target` are in use once it returns. Reclaiming stops early if the
+    /// semaphore is closed. A `target` below the semaphore's minimum, as `with_min`, is raised to
+    /// the minimum.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `target` is negative.
+    pub fn shrink_to(&self, target: isize) {
+        assert!(
+            target >= 0,
+            "cannot shrink to a negative number of resources"
+        );
+        let mut state = self.lock();
+        let target = target.max(state.min);
+        while state.idle > target && !state.closed {
+            let n = (state.idle - target).min(state.count.max(0));
+            if n > 0 {
+                self.take(&mut state, n);
+                state.capacity = state.capacity.map(|c| c - n);
+                state.idle -= n;
+            } else {
+                state = self.wait(&self.condvar, state);
+            }
+        }
+        while state.count < state.idle {
+            state = self.wait(&self.idle, state);
+        }
+    }
+
+    /// Blocks the current thread until at least `n` resources are available, without acquiring
+    /// them.
+    ///
+    /// This suits monitors which act once capacity recovers; by the time the caller acts, other
+    /// threads may have acquired the resources. Returns early if the semaphore is closed, since no
+    /// more resources can become available for acquiring.
+    pub fn wait_for_available(&self, n: isize) {
+        let mut state = self.lock();
+        if state.count >= n || state.closed {
+            return;
+        }
+        state.watchers += 1;
+        while state.count < n && !state.closed {
+            state = self.wait(&self.condvar, state);
+        }
+        state.watchers -= 1;
+    }
+
+    /// Blocks the current thread until the semaphore is idle, with none of its resources held.
+    ///
+    /// The semaphore is idle once its count is back at its capacity, or at its initial count if it
+    /// is uncapped. This keeps working after `close`, so shutdown code can close the semaphore and
+    /// then wait for in-flight work to finish.
+    pub fn wait_for_idle(&self) {
+        let mut state = self.lock();
+        while state.count < state.idle {
+            state = self.wait(&self.idle, state);
+        }
+    }
+
+    /// Blocks the current thread until the semaphore is idle, as `wait_for_idle`, or until the
+    /// timeout elapses.
+    ///
+    /// Returns whether the semaphore became idle before the timeout.
+    pub fn wait_for_idle_timeout(&self, dur: Duration) -> bool {
+        let deadline = Instant::now() + dur;
+        let mut state = self.lock();
+        while state.count < state.idle {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.wait_timeout(&self.idle, state, Some(deadline - now));
+        }
+        true
+    }
+
+    /// Replaces the observer receiving this semaphore's events, or removes it if `observer` is
+    /// `None`.
+    ///
+    /// This is safe to call while other threads are using the semaphore. Events from operations
+    /// which complete after the swap go to the new observer, although an operation racing with
+    /// the swap may still report to the old one.
+    pub fn set_observer(&self, observer: Option<Arc<dyn SemaphoreObserver>>) {
+        let mut current = self.observer.lock().unwrap();
+        self.observed.store(observer.is_some(), Ordering::Relaxed);
+        *current = observer;
+    }
+
+    /// Subscribes to resources becoming available, returning a channel which receives a message
+    /// each time a release leaves resources available.
+    ///
+    /// Subscribing never consumes a resource; it only signals that one may be worth acquiring.
+    /// Each subscriber has its own channel, which buffers at most one message, so further releases
+    /// before the message is received are coalesced into it rather than queueing up without bound.
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<()> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.lock().subscribers.push(Subscriber::Std(tx));
+        rx
+    }
+
+    /// Subscribes to resources becoming available, as `subscribe`, returning a `crossbeam-channel`
+    /// receiver which can be waited on in `crossbeam_channel::select!` alongside other channels.
+    ///
+    /// If resources are already available, the channel starts with a message in it. A message
+    /// only signals that a resource may be worth acquiring, so the branch receiving it should
+    /// follow up with `try_access` and go back to selecting if another thread got there first.
+    /// Since selecting never acquires anything, no resource is lost when another branch wins.
+    #[cfg(feature = "crossbeam")]
+    pub fn subscribe_crossbeam(&self) -> crossbeam_channel::Receiver<()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let mut state = self.lock();
+        if state.count > 0 {
+            let _ = tx.try_send(());
+        }
+        state.subscribers.push(Subscriber::Crossbeam(tx));
+        rx
+    }
+
+    /// Returns a `tokio::sync::watch` receiver which always holds the latest number of available
+    /// resources, so that async tasks can await changes in capacity.
+    ///
+    /// The count is published on every acquisition and release which changes it, from within the
+    /// operation, which only amounts to replacing the value and waking the receivers' tasks. The
+    /// receiver works under any executor. Resources released by `fast_release` are only published
+    /// once they are added to the count.
+    #[cfg(feature = "async")]
+    pub fn watch(&self) -> tokio::sync::watch::Receiver<isize> {
+        let mut state = self.lock();
+        let count = state.count;
+        match &state.count_watch {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = tokio::sync::watch::channel(count);
+                state.count_watch = Some(sender);
+                receiver
+            }
+        }
+    }
+
+    /// Acquires `weight` resources without waiting if they are all available, returning whether
+    /// they were acquired.
+    ///
+    /// Nothing is acquired unless the full weight is available. A weight above the semaphore's
+    /// capacity can never be satisfied, so it fails straight away, as in `acquire_checked_n`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is negative.
+    pub fn try_acquire_weighted(&self, weight: isize) -> bool {
+        assert!(weight >= 0, "cannot acquire a negative number of resources");
+        if self.capacity().is_some_and(|c| weight > c) {
+            return false;
+        }
+        self.try_acquire_n(weight)
+    }
+
+    /// Acquires as many resources as are available right now, up to `max`, without waiting, and
+    /// returns the number acquired.
+    ///
+    /// Unlike an all-or-nothing batch acquisition this takes whatever it can, which may be none.
+    pub fn acquire_up_to(&self, max: isize) -> isize {
+        let started = Instant::now();
+        let mut state = self.lock();
+        if max <= 0 || !self.can_take_unqueued(&state, 1) {
+            return 0;
+        }
+        let n = max.min(state.count);
+        let count = self.take_held(&mut state, n);
+        drop(state);
+        self.observe_acquire(n, started, count);
+        n
+    }
+
+    /// Acquires as many of `n` resources as are available right now without waiting, and returns
+    /// the number acquired, which is `min(available, n)`.
+    ///
+    /// This is `acquire_up_to` for a batch of `n` independent tasks each needing one resource:
+    /// exactly as many tasks as were granted resources can be dispatched straight away, and the
+    /// rest requeued. The caller is responsible for releasing the resources acquired.
+    pub fn try_acquire_up_to_n(&self, n: usize) -> usize {
+        let max = isize::try_from(n).unwrap_or(isize::MAX);
+        self.acquire_up_to(max) as usize
+    }
+
+    /// Attempts to acquire a resource from the semaphore without ever blocking, not even on the
+    /// internal lock.
+    ///
+    /// Returns `None` if the internal lock is momentarily held by another thread, meaning the
+    /// outcome is unknown and the caller should try again later. Otherwise returns `Some(true)` if
+    /// a resource was acquired and `Some(false)` if none were available.
+    ///
+    /// Resources released by `fast_release` which haven't reached the count yet are left for the
+    /// next lock to add, since adding them runs callbacks and wakes threads. If the count is
+    /// empty, one of them is taken directly instead, without ever passing through the count.
+    pub fn try_acquire_nonblocking(&self) -> Option<bool> {
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::WouldBlock) => return None,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        };
+        if self.can_take_unqueued(&state, 1) {
+            self.take_held(&mut state, 1);
+        } else if self.can_take_fast_released(&state) && self.claim_fast_release() {
+            self.note_held(&mut state, 1);
+        } else {
+            return Some(false);
+        }
+        // The observer isn't notified, since taking its lock could block.
+        self.count_acquired(1);
+        Some(true)
+    }
+
+    /// Returns whether a thread which is not in the wait queue may take a resource released by
+    /// `fast_release` straight away, which adding it to the empty count would have allowed.
+    fn can_take_fast_released(&self, state: &State) -> bool {
+        state.count == 0
+            && state.capacity.is_none_or(|c| c > 0)
+            && !state.closed
+            && (!self.fair || state.queue.is_empty())
+    }
+
+    /// Takes one of the resources released by `fast_release` which are yet to be added to the
+    /// count, returning whether there was one.
+    fn claim_fast_release(&self) -> bool {
+        self.fast_releases
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n > 0 {
+                    Some(n - 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Acquires a resource of this semaphore with the given priority, returning an RAII guard to
+    /// release the semaphore when the guard is dropped.
+    pub fn access_priority(&self, priority: u32) -> SemaphoreGuard<'_> {
+        self.acquire_priority(priority);
+        SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard which reports how long the
+    /// resource was held when dropped.
+    ///
+    /// The hold time is measured from when the resource is acquired, not including the time spent
+    /// waiting for it, and `on_release` is invoked with it before the resource is released.
+    pub fn access_timed<F>(&self, on_release: F) -> TimedSemaphoreGuard<'_, F>
+    where
+        F: FnOnce(Duration),
+    {
+        let guard = self.access();
+        TimedSemaphoreGuard {
+            _guard: guard,
+            acquired: Instant::now(),
+            on_release: Some(on_release),
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard which runs `teardown` and
+    /// then releases the resource when dropped.
+    ///
+    /// The resource is only released once `teardown` has returned, so whatever it tears down is
+    /// gone before the next waiter is granted the resource. This suits resources which must never
+    /// overlap between holders.
+    pub fn access_bundle<F>(&self, teardown: F) -> BundleGuard<'_, F>
+    where
+        F: FnOnce(),
+    {
+        BundleGuard {
+            _guard: self.access(),
+            teardown: Some(teardown),
+        }
+    }
+
+    /// Acquires a resource from each of the given semaphores, returning the guards in the same
+    /// order as the semaphores.
+    ///
+    /// The semaphores are always acquired in a fixed global order, whatever order they are passed
+    /// in, so concurrent calls over overlapping sets of semaphores cannot deadlock each other.
+    pub fn acquire_all<'a>(sems: &[&'a Semaphore]) -> Vec<SemaphoreGuard<'a>> {
+        let mut order: Vec<usize> = (0..sems.len()).collect();
+        order.sort_by_key(|&i| sems[i].order_key());
+
+        let mut guards: Vec<Option<SemaphoreGuard<'a>>> = sems.iter().map(|_| None).collect();
+        for i in order {
+            guards[i] = Some(sems[i].access());
+        }
+        guards.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Releases the resources held by all of the given guards, such as those gathered over a phase
+    /// of work.
+    ///
+    /// This is equivalent to dropping each of the guards, but guards for the same semaphore are
+    /// released together, taking its internal lock and notifying waiting threads only once.
+    pub fn release_all(guards: Vec<OwnedSemaphoreGuard>) {
+        let mut releases: Vec<(Arc<Semaphore>, isize)> = Vec::new();
+        for mut guard in guards {
+            match releases
+                .iter_mut()
+                .find(|(sem, _)| Arc::ptr_eq(sem, &guard.sem))
+            {
+                Some((_, n)) => *n += guard.permits,
+                None => releases.push((guard.sem.clone(), guard.permits)),
+            }
+            // The resources are released below instead, so the guard must not release them.
+            guard.permits = 0;
+        }
+        for (sem, n) in releases {
+            sem.release_n(n);
+        }
+    }
+
+    /// Begins an acquisition of a resource, returning a pending acquisition which holds a place in
+    /// the semaphore's wait queue until it is completed or dropped.
+    ///
+    /// The pending acquisition is handed its resource as soon as it reaches the head of the queue
+    /// and one is available, so it never holds up the acquisitions behind it. Once it has been,
+    /// `PendingAcquire::complete` returns straight away, and otherwise it blocks until the
+    /// resource is acquired. Dropping the pending acquisition before completing it cancels it and
+    /// gives back any resource it was handed, so it never consumes one.
+    pub fn start_acquire(&self) -> PendingAcquire<'_> {
+        let mut state = self.lock();
+        let ticket = state.queue.enqueue(0);
+        state.pending.push((ticket, false));
+        self.grant_pending(&mut state);
+        drop(state);
+        PendingAcquire {
+            sem: self,
+            ticket: Some(ticket),
+            started: Instant::now(),
+        }
+    }
+
+    /// Begins an acquisition of a resource, returning a token which can later either wait for the
+    /// resource or cancel the acquisition.
+    pub fn acquire_handle(&self) -> AcquireToken<'_> {
+        AcquireToken {
+            sem: self,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Acquires a resource of this semaphore, returning a guard which releases it every `interval`
+    /// to let waiting threads interleave, then immediately re-acquires it.
+    ///
+    /// This is a cooperative fairness tool for long holds: the holder keeps its place, but never
+    /// starves threads blocked in `acquire` for longer than an interval. A pulse is skipped when
+    /// nobody is waiting, and otherwise gives the waiters up to an interval to take the resource
+    /// before competing for it again, or on a fair semaphore queues behind them. The pulsing
+    /// happens on a background thread for as long as the guard lives, but the resource stays
+    /// recorded as held by the thread which called this, for the debug checks on what threads
+    /// hold.
+    pub fn access_pulsed(self: &Arc<Self>, interval: Duration) -> PulseGuard {
+        self.acquire();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let sem = self.clone();
+        let pulser = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if !sem.pulse(interval, &stopped) {
+                    return false;
+                }
+            }
+            true
+        });
+        PulseGuard {
+            sem: self.clone(),
+            stop: Some(stop),
+            pulser: Some(pulser),
+        }
+    }
+
+    /// Hands the resource held by a `PulseGuard` to a waiting thread if there is one, and takes it
+    /// back on behalf of the guard's owner. Returns whether it was taken back, which fails once
+    /// the semaphore is closed or the guard is dropped, as signalled by `stopped` disconnecting.
+    ///
+    /// The pulsing thread never holds the resource itself, so it leaves the owner's hold records
+    /// alone and restores the owner as the holder once it has taken the resource back.
+    fn pulse(&self, handoff: Duration, stopped: &mpsc::Receiver<()>) -> bool {
+        let state = self.lock();
+        if self.waiters.load(Ordering::Relaxed) == 0 && state.queue.is_empty() {
+            return true;
+        }
+        #[cfg(debug_assertions)]
+        let holder = state.holder;
+        self.give_and_unlock(state, 1);
+        self.observe_release(1);
+
+        let started = Instant::now();
+        let deadline = started + handoff;
+        let mut state = self.lock();
+        let ticket = if self.fair {
+            Some(state.queue.enqueue(0))
+        } else {
+            None
+        };
+        self.batch_waiters.fetch_add(1, Ordering::Relaxed);
+        let taken = loop {
+            if state.closed || matches!(stopped.try_recv(), Err(mpsc::TryRecvError::Disconnected)) {
+                break false;
+            }
+            let timeout = match ticket {
+                Some(ticket) if state.count > 0 && state.queue.head() == Some(ticket) => {
+                    break true
+                }
+                Some(_) => None,
+                None if !self.can_take_unqueued(&state, 1) => None,
+                None => {
+                    // Threads woken by the release stay counted as waiting until they take it.
+                    let now = Instant::now();
+                    if now >= deadline || self.waiters.load(Ordering::Relaxed) == 0 {
+                        break true;
+                    }
+                    Some(deadline - now)
+                }
+            };
+            state = self.wait_timeout(&self.condvar, state, timeout);
+        };
+        self.batch_waiters.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ticket) = ticket {
+            state.queue.remove(ticket);
+        }
+        let count = state.count - 1;
+        if taken {
+            self.take(&mut state, 1);
+            #[cfg(debug_assertions)]
+            {
+                state.holder = holder;
+            }
+        }
+        // Another waiter may be able to proceed now that we have left the queue.
+        if ticket.is_some() && state.count > 0 {
+            self.notify(&mut state, 1);
+        }
+        drop(state);
+        if taken {
+            self.observe_acquire(1, started, count);
+        }
+        taken
+    }
+
+    /// Acquires `n` resources of this semaphore one at a time, returning an owned RAII guard for
+    /// each of them.
+    ///
+    /// Blocks until all `n` resources have been acquired.
+    pub fn access_many_owned(self: &Arc<Self>, n: usize) -> Vec<OwnedSemaphoreGuard> {
+        (0..n).map(|_| self.access_owned()).collect()
+    }
+
+    /// Acquires up to `n` resources of this semaphore without waiting, returning an owned RAII
+    /// guard for each resource that was available.
+    pub fn try_access_many_owned(self: &Arc<Self>, n: usize) -> Vec<OwnedSemaphoreGuard> {
+        (0..n).map_while(|_| self.try_access_owned()).collect()
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard to release the semaphore
+    /// when the guard is dropped, or an error if the semaphore is closed.
+    pub fn access_checked(&self) -> Result<SemaphoreGuard<'_>, AcquireError> {
+        self.acquire_checked()?;
+        Ok(SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Acquires a resource of this semaphore asynchronously, completing once the resource has
+    /// been acquired.
+    ///
+    /// The returned future works under any executor: it registers the `Waker` from the context it
+    /// is polled with, and is woken when resources are released. Dropping the future before it
+    /// completes never consumes a resource.
+    ///
+    /// On a fair semaphore the future joins the same wait queue as blocking acquisitions when it
+    /// is first polled, so sync and async waiters are served strictly in arrival order. On an
+    /// unfair semaphore a release wakes both a blocked thread and every pending future, and
+    /// whichever reaches the semaphore first takes the resource.
+    ///
+    /// # Panics
+    ///
+    /// The future panics if the semaphore is closed, either before or while waiting.
+    #[cfg(feature = "async")]
+    pub async fn acquire_async(&self) {
+        Acquire::new(self)
+            .await
+            .expect("acquired from a closed semaphore")
+    }
+
+    /// Acquires a resource of this semaphore asynchronously, returning an RAII guard to release
+    /// the semaphore when the guard is dropped.
+    #[cfg(feature = "async")]
+    pub async fn access_async(&self) -> SemaphoreGuard<'_> {
+        self.acquire_async().await;
+        SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        }
+    }
+
+    /// Returns a stream which acquires a resource of this semaphore each time it is polled for the
+    /// next item, yielding an owned guard releasing it.
+    ///
+    /// Each resource is acquired as by `acquire_async`, so dropping the stream while it waits for
+    /// a resource never consumes one. The stream ends once the semaphore is closed.
+    #[cfg(feature = "async")]
+    pub fn permit_stream(self: &Arc<Self>) -> PermitStream {
+        PermitStream {
+            sem: self.clone(),
+            pending: None,
+        }
+    }
+
+    /// Acquires `N` resources at once, blocking until they are all available, and returns them as
+    /// an array of guards each releasing one resource when dropped.
+    ///
+    /// The resources are acquired together, as `acquire_checked_n`, so two threads acquiring
+    /// arrays can't deadlock each holding part of a batch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is more than the semaphore's capacity, or if the semaphore is closed.
+    pub fn access_array<const N: usize>(&self) -> [SemaphoreGuard<'_>; N] {
+        let mut batch = match isize::try_from(N) {
+            Ok(n) => self.acquire_checked_n(n),
+            Err(_) => Err(AcquireError::Unsatisfiable),
+        }
+        .unwrap_or_else(|e| panic!("failed to acquire resources: {}", e));
+        batch.permits = 0;
+        std::array::from_fn(|_| SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Acquires as many resources of this semaphore as are available right now, up to `max`,
+    /// returning an RAII guard which releases exactly that many when dropped.
+    pub fn access_up_to(&self, max: isize) -> SemaphoreGuard<'_> {
+        SemaphoreGuard {
+            sem: self,
+            permits: self.acquire_up_to(max),
+        }
+    }
+
+    /// Attempts to acquire every resource of this semaphore at once without waiting, returning an
+    /// RAII guard releasing them all if no resource was held.
+    ///
+    /// This takes the semaphore exclusively, as a non-blocking write lock would, and fails if even
+    /// one resource is held or the semaphore has none to give.
+    pub fn try_acquire_all(&self) -> Option<SemaphoreGuard<'_>> {
+        let started = Instant::now();
+        let mut state = self.lock();
+        let n = state.count;
+        if n < state.idle || !self.can_take_unqueued(&state, n) {
+            return None;
+        }
+        let count = self.take_held(&mut state, n);
+        drop(state);
+        self.observe_acquire(n, started, count);
+        Some(SemaphoreGuard {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    /// Attempts to acquire a resource of this semaphore without waiting, returning an RAII guard
+    /// if one was available or the reason it could not be acquired otherwise.
+    pub fn try_access_detailed(&self) -> Result<SemaphoreGuard<'_>, TryAccessError> {
+        let started = Instant::now();
+        let mut state = self.lock();
+        if state.closed {
+            return Err(TryAccessError::Closed);
+        }
+        if !self.can_take_unqueued(&state, 1) {
+            return Err(if self.wait_queue_full(&state) {
+                TryAccessError::QueueFull
+            } else {
+                TryAccessError::NoPermits
+            });
+        }
+        let count = self.take_held(&mut state, 1);
+        drop(state);
+        self.observe_acquire(1, started, count);
+        Ok(SemaphoreGuard {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Takes each resource that is available right now, without waiting, and invokes `f` with a
+    /// guard for it.
+    ///
+    /// The number of resources taken is fixed when this is called: a guard dropped at the end of
+    /// `f` releases its resource, but it is not taken again. Stops early if other threads take
+    /// the remaining resources first.
+    pub fn drain_with<'a, F>(&'a self, mut f: F)
+    where
+        F: FnMut(SemaphoreGuard<'a>),
+    {
+        for _ in 0..self.available_permits().max(0) {
+            match self.try_access() {
+                Some(guard) => f(guard),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the number of resources currently available.
+    ///
+    /// This may be negative if the semaphore was initialized with a negative count. The value is
+    /// only a snapshot and may be stale by the time it is used.
+    #[inline]
+    pub fn available_permits(&self) -> isize {
+        self.lock().count
+    }
+
+    /// Returns the semaphore with the most resources available, for balancing load across several
+    /// semaphores.
+    ///
+    /// Each count is sampled once, so the choice may be stale by the time it is used, as
+    /// `available_permits`. Ties go to the semaphore earliest in the slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sems` is empty.
+    pub fn most_available<'a>(sems: &[&'a Semaphore]) -> &'a Semaphore {
+        let mut best: Option<(&'a Semaphore, isize)> = None;
+        for &sem in sems {
+            let available = sem.available_permits();
+            if best.is_none_or(|(_, most)| available > most) {
+                best = Some((sem, available));
+            }
+        }
+        match best {
+            Some((sem, _)) => sem,
+            None => panic!("no semaphores to choose from"),
+        }
+    }
+
+    /// Returns the maximum count of a semaphore created by `with_max`, or `None` if the
+    /// semaphore is uncapped.
+    ///
+    /// Together with `available_permits` this gives the semaphore's utilization.
+    pub fn capacity(&self) -> Option<isize> {
+        self.lock().capacity
+    }
+
+    /// Returns the number of threads currently blocked waiting to acquire resources.
+    ///
+    /// This doesn't lock the semaphore, so it is cheap enough to sample frequently, but like any
+    /// count of waiters it may be out of date as soon as it is returned.
+    pub fn waiter_count(&self) -> usize {
+        self.waiters.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every release started on any thread so far has reached the count, then returns
+    /// the number of resources available.
+    ///
+    /// A release can return before its resources are counted, when releases are coalesced and
+    /// another thread's release adds them, so `available_permits` may briefly lag behind releases
+    /// which other threads have already seen return. This gives tests a stable reading instead.
+    /// Acquisitions change the count in one step, so they never need waiting for. This waits for
+    /// a moment with no release in progress, so it may wait indefinitely while other threads keep
+    /// releasing. Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn sync_point(&self) -> isize {
+        while self.in_flight.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        self.lock().count
+    }
+
+    /// Returns whether any thread has acquired a resource from this semaphore while already
+    /// holding one from it.
+    ///
+    /// Such nested holds work as long as resources are available, but deadlock once every
+    /// resource is held by a thread waiting for another, so this helps audit code for them. Only
+    /// available with the `nested-holds` feature.
+    #[cfg(feature = "nested-holds")]
+    pub fn nested_hold_detected(&self) -> bool {
+        self.nested_hold.load(Ordering::Relaxed)
+    }
+
+    /// Returns the longest any acquisition of this semaphore has waited for its resources.
+    ///
+    /// This is a high-water mark which is never reset, and reading it doesn't lock the semaphore.
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_nanos(
+            self.max_wait_nanos
+                .load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the ticket of the queued waiter which will be served next, or `None` if no thread
+    /// is queued.
+    ///
+    /// Tickets are handed out in arrival order to every waiter on a fair semaphore, and to
+    /// priority waiters on any semaphore. This is diagnostic information only; it may change as
+    /// soon as it is returned.
+    pub fn next_waiter_ticket(&self) -> Option<u64> {
+        self.lock().queue.head()
+    }
+
+    /// Returns the number of blocking acquisitions which had to wait for a resource rather than
+    /// finding one immediately available.
+    ///
+    /// Compared against the total number of acquisitions, this shows whether the semaphore needs
+    /// more capacity.
+    pub fn contention_count(&self) -> u64 {
+        self.lock().contended_acquires
+    }
+
+    /// Returns a snapshot of the semaphore's counters, all read under a single lock of its state.
+    ///
+    /// Reading the counters through the separate getters can interleave with acquisitions and
+    /// releases, so their values needn't agree with each other. The snapshot's do: on a capped
+    /// semaphore, `available + outstanding` is always exactly the capacity.
+    pub fn checkpoint(&self) -> SemaphoreSnapshot {
+        let state = self.lock();
+        // The waiter counts are only changed with the state locked, so they agree with it here.
+        SemaphoreSnapshot {
+            available: state.count,
+            capacity: state.capacity,
+            outstanding: state.idle - state.count,
+            waiters: self.waiter_count(),
+            contended_acquires: state.contended_acquires,
+        }
+    }
+
+    /// Renders the semaphore's metrics in the Prometheus text exposition format, for serving from
+    /// a `/metrics` endpoint.
+    ///
+    /// The available count and the number of blocked threads are exported as gauges, and the
+    /// total resources acquired and released as counters. Totals are only kept by semaphores
+    /// created by `with_metrics_name`; other semaphores export just the gauges, prefixed with
+    /// `semaphore`.
+    pub fn render_prometheus(&self) -> String {
+        let count = self.lock().count;
+        let waiters = self.waiter_count();
+        let name = self
+            .metrics
+            .as_ref()
+            .map_or("semaphore", |m| m.name.as_str());
+
+        let mut out = String::new();
+        let mut metric = |suffix: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {}_{} {}", name, suffix, help);
+            let _ = writeln!(out, "# TYPE {}_{} {}", name, suffix, kind);
+            let _ = writeln!(out, "{}_{} {}", name, suffix, value);
+        };
+        metric(
+            "available",
+            "gauge",
+            "Resources currently available.",
+            count.to_string(),
+        );
+        metric(
+            "waiters",
+            "gauge",
+            "Threads blocked waiting for a resource.",
+            waiters.to_string(),
+        );
+        if let Some(metrics) = &self.metrics {
+            let acquired = metrics.acquired.load(Ordering::Relaxed);
+            let released = metrics.released.load(Ordering::Relaxed);
+            metric(
+                "acquired_total",
+                "counter",
+                "Resources acquired.",
+                acquired.to_string(),
+            );
+            metric(
+                "released_total",
+                "counter",
+                "Resources released.",
+                released.to_string(),
+            );
+        }
+        out
+    }
+
+    /// Consumes the semaphore, returning its final count.
+    ///
+    /// Taking the semaphore by value guarantees that no guards are outstanding. A lock poisoned by
+    /// a thread which panicked while using the semaphore is recovered from, since the count is
+    /// still meaningful.
+    pub fn into_inner(self) -> isize {
+        let fast_releases = self.fast_releases.into_inner();
+        let state = self
+            .state
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        let limit = state.capacity.unwrap_or(isize::MAX);
+        state.count.saturating_add(fast_releases).min(limit)
+    }
+}
+
+impl<'scope, 'env> SemaphoreScope<'scope, 'env> {
+    /// Acquires a resource, blocking until one is available, and returns a guard releasing it.
+    pub fn acquire(&'scope self) -> ScopedGuard<'scope> {
+        self.sem.acquire();
+        self.guard()
+    }
+
+    /// Attempts to acquire a resource without waiting.
+    pub fn try_acquire(&'scope self) -> Option<ScopedGuard<'scope>> {
+        if self.sem.try_acquire() {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of resources acquired through the scope which are yet to be released.
+    pub fn held(&self) -> isize {
+        self.held.load(Ordering::SeqCst)
+    }
+
+    /// Records a resource acquired through the scope and returns its guard.
+    fn guard(&'scope self) -> ScopedGuard<'scope> {
+        self.held.fetch_add(1, Ordering::SeqCst);
+        ScopedGuard {
+            held: &self.held,
+            sem: self.sem,
+        }
+    }
+}
+
+impl<'scope> Drop for ScopedGuard<'scope> {
+    fn drop(&mut self) {
+        self.held.fetch_sub(1, Ordering::SeqCst);
+        self.sem.release();
+    }
+}
+
+// Stop pulsing before releasing, so that the resource is released exactly once and never taken
+// back afterwards.
+impl Drop for PulseGuard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        // The pulser checks for the stop with the state locked before it waits, so this can't be
+        // missed.
+        drop(self.sem.lock());
+        self.sem.condvar.notify_all();
+        let pulser = self.pulser.take().unwrap();
+        if let Ok(true) = pulser.join() {
+            self.sem.release();
+        } else {
+            self.sem.note_released(1);
+        }
+    }
+}
+
+impl<'a> ChildSemaphore<'a> {
+    /// Returns the number of resources reserved from the parent.
+    pub fn reserved(&self) -> isize {
+        self.reservation.permits
+    }
+}
+
+impl<'a> Deref for ChildSemaphore<'a> {
+    type Target = Semaphore;
+
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+impl<'a> SemaphoreGuard<'a> {
+    /// Returns the number of resources the guard currently holds, and will release when dropped.
+    pub fn permits_held(&self) -> isize {
+        self.permits
+    }
+
+    /// Releases `n` of the guard's resources early, keeping the rest until the guard is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is negative or more than the guard holds.
+    pub fn release_some(&mut self, n: isize) {
+        assert!(
+            0 <= n && n <= self.permits,
+            "cannot release {} of a guard's {} resources",
+            n,
+            self.permits
+        );
+        self.permits -= n;
+        self.sem.release_n(n);
+    }
+
+    /// Releases the guard's resources only if `pred` returns `true`, and otherwise permanently
+    /// removes them from the semaphore, lowering its capacity if it has one, as `forget` does
+    /// for available resources. Returns whether the resources were released.
+    ///
+    /// This suits resources which should only be returned while still healthy, with `pred`
+    /// checking their health when they are given back.
+    pub fn release_if<F>(mut self, pred: F) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        let permits = std::mem::replace(&mut self.permits, 0);
+        if pred() {
+            self.sem.release_n(permits);
+            true
+        } else {
+            self.sem.discard(permits);
+            false
+        }
+    }
+
+    /// Gives the guard's resources back while `f` runs, so other threads may acquire them in the
+    /// meantime, then blocks until they have all been reacquired and returns what `f` returned.
+    ///
+    /// The guard holds nothing while `f` runs, so if `f` panics the resources are not released
+    /// a second time when the guard is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the resources can't be reacquired because the semaphore was closed or its
+    /// capacity lowered below what the guard held.
+    pub fn yield_for<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let permits = std::mem::replace(&mut self.permits, 0);
+        self.sem.release_n(permits);
+        let result = f();
+        unwrap_acquired(self.sem.acquire_checked_n(permits).map(|mut guard| {
+            self.permits = std::mem::replace(&mut guard.permits, 0);
+        }));
+        result
+    }
+
+    /// Converts the guard into an owned guard, which can be sent to other threads, given the
+    /// `Arc` holding the guarded semaphore.
+    ///
+    /// The resources are transferred to the returned guard and released exactly once, when it is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sem` is not the semaphore this guard was acquired from.
+    pub fn into_owned(mut self, sem: Arc<Semaphore>) -> OwnedSemaphoreGuard {
+        assert!(
+            std::ptr::eq(self.sem, &*sem),
+            "guard converted with a different semaphore"
+        );
+        let permits = std::mem::replace(&mut self.permits, 0);
+        OwnedSemaphoreGuard { sem, permits }
+    }
+}
+
+impl OwnedSemaphoreGuard {
+    /// Converts the guard into a guard borrowing the semaphore, given a borrow of the guarded
+    /// semaphore which outlives it.
+    ///
+    /// The resources are transferred to the returned guard and released exactly once, when it is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sem` is not the semaphore this guard was acquired from.
+    pub fn into_borrowed(mut self, sem: &Semaphore) -> SemaphoreGuard<'_> {
+        assert!(
+            std::ptr::eq(&*self.sem, sem),
+            "guard converted with a different semaphore"
+        );
+        let permits = std::mem::replace(&mut self.permits, 0);
+        SemaphoreGuard { sem, permits }
+    }
+
+    /// Returns the semaphore the guard's resources were acquired from.
+    pub fn semaphore(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+impl SemaphoreBuilder {
+    /// Sets whether the semaphore is fair, serving waiting threads in arrival order. This is
+    /// shorthand for an ordering of `WaiterOrder::Fifo` or `WaiterOrder::Unordered`.
+    pub fn fair(mut self, fair: bool) -> Self {
+        self.ordering = if fair {
+            WaiterOrder::Fifo
+        } else {
+            WaiterOrder::Unordered
+        };
+        self
+    }
+
+    /// Sets the order in which waiting threads are served.
+    pub fn ordering(mut self, ordering: WaiterOrder) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Sets the maximum count of the semaphore.
+    pub fn max(mut self, max: isize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Sets the fewest resources the semaphore may be shrunk to.
+    pub fn min(mut self, min: isize) -> Self {
+        self.min = min;
+        self
+    }
+
+    /// Sets the most threads which may block waiting for a resource at once.
+    pub fn max_waiters(mut self, max_waiters: usize) -> Self {
+        self.max_waiters = Some(max_waiters);
+        self
+    }
+
+    /// Sets the rate priority waiters age at, in priority levels per second.
+    pub fn priority_aging(mut self, rate: f64) -> Self {
+        self.aging_rate = rate;
+        self
+    }
+
+    /// Sets whether releases notify waiting threads only after unlocking the semaphore's internal
+    /// state.
+    ///
+    /// By default a waiting thread is notified while the releasing thread still holds the lock,
+    /// so on some platforms it wakes only to block again until the lock is released. Notifying
+    /// after unlocking avoids that at the cost of the notification happening slightly later.
+    pub fn notify_after_unlock(mut self, after_unlock: bool) -> Self {
+        self.notify_after_unlock = after_unlock;
+        self
+    }
+
+    /// Sets whether concurrent releases are coalesced into one update of the semaphore.
+    ///
+    /// A coalescing release first adds to a pending count kept outside the internal lock. Only a
+    /// release which finds nothing pending takes the lock, and it adds everything pending by the
+    /// time it gets the lock to the count with a single notification; releases arriving in the
+    /// meantime return straight away. This cuts down lock traffic when many threads release at
+    /// once, but a release may return slightly before its resource is visible to other threads.
+    pub fn coalesce_releases(mut self, coalesce: bool) -> Self {
+        self.coalesce_releases = coalesce;
+        self
+    }
+
+    /// Sets whether releasing several resources at once wakes only as many blocked threads as
+    /// resources released, which is the default, rather than every blocked thread.
+    ///
+    /// Waking everyone makes all but the winners go straight back to sleep, which wastes wakeups
+    /// when many threads wait for one resource each. Every blocked thread is still woken when
+    /// some may take other than one resource each, or when threads are queued by priority.
+    pub fn exact_wakeups(mut self, exact: bool) -> Self {
+        self.exact_wakeups = exact;
+        self
+    }
+
+    /// Sets whether, in debug builds, a thread which holds the only resource of a semaphore with a
+    /// single resource panics when it tries to acquire it again, rather than deadlocking. This is
+    /// on by default.
+    ///
+    /// The check goes by which thread took the resource, so it must be turned off for semaphores
+    /// whose resources are released by other threads than the ones which acquired them, as when
+    /// an owned guard is handed to another thread or the semaphore signals between threads: a
+    /// thread waiting there for a resource it took itself is not a deadlock, but would be reported
+    /// as one. Release builds never check.
+    pub fn detect_reentrant_acquire(mut self, detect: bool) -> Self {
+        self.detect_reentrant_acquire = detect;
+        self
+    }
+
+    /// Sets whether a release prefers to serve a queued waiter which last ran on the same CPU as
+    /// the releasing thread, or failing that on the same NUMA node, over the waiter the ordering
+    /// would otherwise serve next. Only available with the `numa` feature.
+    ///
+    /// This keeps the protected data in caches close to the threads using it on large
+    /// multi-socket machines. Only waiters ranked equally by the ordering and their priorities
+    /// compete on locality, so it only affects the waiters a fair or LIFO ordering queues, and
+    /// the waiter which would otherwise be next is bypassed at most a few times in a row, so no
+    /// waiter is starved.
+    #[cfg(feature = "numa")]
+    pub fn prefer_local_waiters(mut self, prefer: bool) -> Self {
+        self.prefer_local_waiters = prefer;
+        self
+    }
+
+    /// Builds the semaphore.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a maximum was set which is less than the initial count, or a minimum which is
+    /// negative or greater than it.
+    pub fn build(self) -> Semaphore {
+        assert!(self.min >= 0, "minimum must not be negative");
+        assert!(self.count >= self.min, "initial count is below the minimum");
+        let mut sem = Semaphore::with_priority_aging(self.count, self.aging_rate);
+        sem.fair = self.ordering != WaiterOrder::Unordered;
+        sem.notify_after_unlock = self.notify_after_unlock;
+        sem.coalesce_releases = self.coalesce_releases;
+        sem.exact_wakeups = self.exact_wakeups;
+        let state = sem.state.get_mut().unwrap();
+        if let Some(max) = self.max {
+            assert!(self.count <= max, "initial count exceeds the maximum");
+            state.capacity = Some(max);
+            state.idle = max;
+        }
+        state.min = self.min;
+        state.max_waiters = self.max_waiters;
+        #[cfg(debug_assertions)]
+        {
+            state.track_holder = self.detect_reentrant_acquire;
+        }
+        state.queue.set_lifo(self.ordering == WaiterOrder::Lifo);
+        #[cfg(feature = "numa")]
+        state.queue.set_locality(self.prefer_local_waiters);
+        sem
+    }
+}
+
+impl<'a> AcquireToken<'a> {
+    /// Blocks until a resource is acquired, the acquisition is cancelled or the semaphore is
+    /// closed.
+    ///
+    /// Returns `true` if a resource was acquired, in which case the caller is responsible for
+    /// releasing it, and `false` otherwise, including if the wait queue is full.
+    pub fn wait(&self) -> bool {
+        self.wait_checked().is_ok()
+    }
+
+    /// Blocks until a resource is acquired, failing with `AcquireError::Cancelled` once the
+    /// acquisition is cancelled, with `AcquireError::Closed` if the semaphore is closed and with
+    /// `AcquireError::QueueFull` if its wait queue is full.
+    ///
+    /// On success the caller is responsible for releasing the resource.
+    pub fn wait_checked(&self) -> Result<(), AcquireError> {
+        self.sem.check_lock_order();
+        let started = Instant::now();
+        let mut state = self.sem.lock();
+        let mut waiting = false;
+        let result = loop {
+            if state.closed {
+                break Err(AcquireError::Closed);
+            }
+            if self.cancelled.load(Ordering::SeqCst) {
+                // We may have consumed a notification meant for another waiter, so pass it on.
+                if state.count > 0 {
+                    self.sem.notify(&mut state, 1);
+                }
+                break Err(AcquireError::Cancelled);
+            }
+            if self.sem.can_take_unqueued(&state, 1) {
+                break Ok(self.sem.take_held(&mut state, 1));
+            }
+            if !waiting {
+                if let Err(err) = self.sem.start_waiting(&mut state) {
+                    break Err(err);
+                }
+                waiting = true;
+            }
+            state = self.sem.wait(&self.sem.condvar, state);
+        };
+        if waiting {
+            self.sem.stop_waiting();
+        }
+        drop(state);
+        let count = result?;
+        self.sem.observe_acquire(1, started, count);
+        Ok(())
+    }
+
+    /// Cancels the acquisition, waking `wait` if it is blocked, without consuming a resource.
+    ///
+    /// Has no effect if the acquisition has already completed.
+    pub fn cancel(&self) {
+        cancel_acquire(self.sem, &self.cancelled);
+    }
+
+    /// Returns a handle which can cancel this acquisition from another thread, including while
+    /// `wait` is blocked.
+    pub fn canceller(&self) -> AcquireCanceller<'a> {
+        AcquireCanceller {
+            sem: self.sem,
+            cancelled: self.cancelled.clone(),
+        }
+    }
+}
+
+impl<'a> PendingAcquire<'a> {
+    /// Blocks until the resource is acquired, returning an RAII guard to release it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is closed, either before or while waiting, or if its wait queue is
+    /// full.
+    pub fn complete(mut self) -> SemaphoreGuard<'a> {
+        let ticket = self.ticket.take().unwrap();
+        self.sem.check_lock_order();
+        let mut state = self.sem.lock();
+        let count = if take_pending(&mut state, ticket) {
+            self.sem.note_held(&mut state, 1);
+            state.count
+        } else {
+            unwrap_acquired(self.sem.wait_queued(state, ticket))
+        };
+        self.sem.observe_acquire(1, self.started, count);
+        SemaphoreGuard {
+            sem: self.sem,
+            permits: 1,
+        }
+    }
+}
+
+impl<'a> Drop for PendingAcquire<'a> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            let mut state = self.sem.lock();
+            if take_pending(&mut state, ticket) {
+                self.sem.give_and_unlock(state, 1);
+                return;
+            }
+            // The waiter behind us may have been held up only by our place in the queue.
+            state.queue.remove(ticket);
+            if state.count > 0 && !state.closed {
+                self.sem.notify(&mut state, 1);
+            }
+        }
+    }
+}
+
+impl<'a> HandoffToken<'a> {
+    /// Returns the ticket a producer passes to `Semaphore::release_to` to hand this consumer a
+    /// resource.
+    pub fn ticket(&self) -> HandoffTicket<'a> {
+        HandoffTicket {
+            sem: self.sem,
+            id: self.id,
+        }
+    }
+
+    /// Blocks until a producer hands the consumer a resource, returning an RAII guard to release
+    /// it.
+    ///
+    /// Resources released in any other way are left to the other waiters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is closed before a resource is handed over.
+    pub fn wait(self) -> SemaphoreGuard<'a> {
+        self.sem.check_lock_order();
+        let started = Instant::now();
+        let mut state = self.sem.lock();
+        loop {
+            // The registration is only removed by the token, so it is there until we take it.
+            let i = state
+                .handoffs
+                .iter()
+                .position(|(id, _)| *id == self.id)
+                .unwrap();
+            if state.handoffs[i].1.granted {
+                state.handoffs.remove(i);
+                self.sem.note_held(&mut state, 1);
+                let count = state.count;
+                drop(state);
+                self.sem.observe_acquire(1, started, count);
+                return SemaphoreGuard {
+                    sem: self.sem,
+                    permits: 1,
+                };
+            }
+            if state.closed {
+                drop(state);
+                panic!("acquired from a closed semaphore");
+            }
+
+            // The thread is registered before the state is unlocked, so a producer handing the
+            // resource over after the check above always finds it to unpark.
+            state.handoffs[i].1.waiter = Some(thread::current());
+            drop(state);
+            thread::park();
+            state = self.sem.lock();
+        }
+    }
+}
+
+impl<'a> AcquireCanceller<'a> {
+    /// Cancels the acquisition, waking it if it is blocked in `wait`.
+    ///
+    /// Has no effect if the acquisition has already completed.
+    pub fn cancel(&self) {
+        cancel_acquire(self.sem, &self.cancelled);
+    }
+}
+
+/// Marks an `AcquireToken` cancelled and wakes its `wait`, if it is blocked.
+fn cancel_acquire(sem: &Semaphore, cancelled: &AtomicBool) {
+    cancelled.store(true, Ordering::SeqCst);
+    // Taking the lock ensures the waiter is either before its check of the flag or parked on the
+    // condvar, so the notification cannot be missed.
+    drop(sem.lock());
+    sem.condvar.notify_all();
+}
+
+/// A future acquiring a resource from a semaphore, either borrowed or shared through an `Arc`.
+#[cfg(feature = "async")]
+struct Acquire<S: Deref<Target = Semaphore>> {
+    /// The semaphore to acquire from.
+    sem: S,
+    /// The id of this future's waker registration, while it has one.
+    waker: Option<u64>,
+    /// This future's ticket in the wait queue of a fair semaphore, while it is queued.
+    ticket: Option<u64>,
+    /// When the acquisition started.
+    started: Instant,
+}
+
+#[cfg(feature = "async")]
+impl<S: Deref<Target = Semaphore>> Acquire<S> {
+    fn new(sem: S) -> Self {
+        Acquire {
+            sem,
+            waker: None,
+            ticket: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: Deref<Target = Semaphore> + Unpin> Future for Acquire<S> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let sem = &*this.sem;
+        let mut state = sem.lock();
+        if let Some(id) = this.waker.take() {
+            state.wakers.retain(|(other, _)| *other != id);
+            sem.wakers_changed(&state);
+        }
+
+        if state.closed {
+            if let Some(ticket) = this.ticket.take() {
+                state.queue.remove(ticket);
+            }
+            return Poll::Ready(Err(AcquireError::Closed));
+        }
+
+        // On a fair semaphore the future queues alongside blocking waiters from its first poll,
+        // so that the two kinds are served in arrival order.
+        if sem.fair && this.ticket.is_none() {
+            this.ticket = Some(state.queue.enqueue(0));
+        }
+        let ready = match this.ticket {
+            Some(ticket) => state.count > 0 && state.queue.head() == Some(ticket),
+            None => sem.can_take_unqueued(&state, 1),
+        };
+        if ready {
+            let count = if let Some(ticket) = this.ticket.take() {
+                state.queue.remove(ticket);
+                let count = sem.take_held(&mut state, 1);
+                // Another waiter may be able to proceed now that we have left the queue.
+                if state.count > 0 {
+                    sem.notify(&mut state, 1);
+                }
+                count
+            } else {
+                sem.take_held(&mut state, 1)
+            };
+            drop(state);
+            sem.observe_acquire(1, this.started, count);
+            return Poll::Ready(Ok(()));
+        }
+
+        let id = state.next_waker;
+        state.next_waker += 1;
+        state.wakers.push((id, cx.waker().clone()));
+        sem.wakers_changed(&state);
+        drop(state);
+        this.waker = Some(id);
+        // As in `Semaphore::wait_timeout`, a fast release racing with the registration is either
+        // seen here or sees the registration.
+        if sem.fast_releases.load(Ordering::SeqCst) != 0 {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: Deref<Target = Semaphore>> Drop for Acquire<S> {
+    fn drop(&mut self) {
+        if self.waker.is_none() && self.ticket.is_none() {
+            return;
+        }
+        let mut state = self.sem.lock();
+        if let Some(id) = self.waker {
+            state.wakers.retain(|(other, _)| *other != id);
+            self.sem.wakers_changed(&state);
+        }
+        if let Some(ticket) = self.ticket {
+            // The waiter behind us may have been held up only by our place in the queue.
+            state.queue.remove(ticket);
+            if state.count > 0 && !state.closed {
+                self.sem.notify(&mut state, 1);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl futures_core::Stream for PermitStream {
+    type Item = OwnedSemaphoreGuard;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let sem = &this.sem;
+        let acquire = this
+            .pending
+            .get_or_insert_with(|| Acquire::new(sem.clone()));
+        let acquired = match Pin::new(acquire).poll(cx) {
+            Poll::Ready(acquired) => acquired,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.pending = None;
+        Poll::Ready(acquired.ok().map(|()| OwnedSemaphoreGuard {
+            sem: this.sem.clone(),
+            permits: 1,
+        }))
+    }
+}
+
+/// Publishes the count to the receivers returned by `watch`, if it has changed, and stops
+/// publishing once every receiver has been dropped.
+#[cfg(feature = "async")]
+fn publish_count(state: &mut State) {
+    let count = state.count;
+    if let Some(sender) = &state.count_watch {
+        if sender.is_closed() {
+            state.count_watch = None;
+            return;
+        }
+        sender.send_if_modified(|published| {
+            let modified = *published != count;
+            *published = count;
+            modified
+        });
+    }
+}
+
+/// Unparks every thread parked in `acquire_parkable`.
+fn unpark_all(state: &mut State) {
+    for (_, thread) in state.parked.drain(..) {
+        thread.unpark();
+    }
+}
+
+/// A source of random backoff durations, using an xorshift generator seeded from the standard
+/// library's randomized hasher.
+struct Jitter {
+    /// The generator's state, which is never zero.
+    state: u64,
+    /// The shortest backoff.
+    min: Duration,
+    /// The longest backoff.
+    max: Duration,
+}
+
+impl Jitter {
+    fn new(min: Duration, max: Duration) -> Self {
+        Jitter {
+            state: RandomState::new().hash_one(Instant::now()) | 1,
+            min,
+            max,
+        }
+    }
+
+    /// Yields the current thread for the next random duration.
+    fn wait(&mut self) {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let fraction = (self.state >> 11) as f64 / (1u64 << 53) as f64;
+        let deadline = Instant::now() + self.min + (self.max - self.min).mul_f64(fraction);
+        while Instant::now() < deadline {
+            thread::yield_now();
+        }
+    }
+}
+
+impl Subscriber {
+    /// Notifies the subscriber that resources are available, returning whether it is still
+    /// subscribed.
+    ///
+    /// A full channel already has a notification pending, and a disconnected one has no receiver
+    /// left to read it.
+    fn notify(&self) -> bool {
+        match self {
+            Subscriber::Std(tx) => !matches!(tx.try_send(()), Err(TrySendError::Disconnected(_))),
+            #[cfg(feature = "crossbeam")]
+            Subscriber::Crossbeam(tx) => !matches!(
+                tx.try_send(()),
+                Err(crossbeam_channel::TrySendError::Disconnected(_))
+            ),
+        }
+    }
+}
+
+/// Summarizes the semaphore's state on one line, for logging.
+///
+/// The internal lock is only tried, never waited for, so formatting can't stall behind other
+/// users of the semaphore; if it is held, the state is printed as `<locked>`.
+impl fmt::Display for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return write!(f, "Semaphore {{ <locked> }}"),
+        };
+        write!(
+            f,
+            "Semaphore {{ available: {}, waiters: {}, capacity: {:?} }}",
+            state.count,
+            self.waiter_count(),
+            state.capacity
+        )
+    }
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcquireError::Closed => write!(f, "semaphore closed"),
+            AcquireError::QueueFull => write!(f, "semaphore wait queue full"),
+            AcquireError::Unsatisfiable => write!(f, "request exceeds semaphore capacity"),
+            AcquireError::Cancelled => write!(f, "acquisition cancelled"),
+        }
+    }
+}
+
+impl Error for AcquireError {}
+
+impl fmt::Display for TryAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryAccessError::NoPermits => write!(f, "no resources available"),
+            TryAccessError::Closed => write!(f, "semaphore closed"),
+            TryAccessError::QueueFull => write!(f, "semaphore wait queue full"),
+        }
+    }
+}
+
+impl Error for TryAccessError {}
+
+/// Returns whether the current thread is about to wait for the only resource of a semaphore with
+/// a single resource while holding it itself, which would deadlock. The holder is only tracked in
+/// debug builds of semaphores which haven't opted out with `for_signalling`, so this is always
+/// false otherwise.
+#[cfg_attr(not(debug_assertions), allow(unused_variables))]
+fn holds_only_resource(state: &State) -> bool {
+    #[cfg(debug_assertions)]
+    return state.count <= 0
+        && state.idle == 1
+        && state.holder == Some(crate::sync::current_thread_id());
+    #[cfg(not(debug_assertions))]
+    return false;
+}
+
+/// Invokes the `close_with` callback for `n` resources released by their holders, if the semaphore
+/// is closed.
+fn orphan(state: &State, n: isize) {
+    if !state.closed {
+        return;
+    }
+    if let Some(on_orphan) = &state.on_orphan {
+        for _ in 0..n {
+            on_orphan();
+        }
+    }
+}
+
+/// Stops tracking the pending acquisition with the given ticket, returning whether it had been
+/// handed its resource.
+fn take_pending(state: &mut State, ticket: u64) -> bool {
+    let i = state
+        .pending
+        .iter()
+        .position(|&(other, _)| other == ticket)
+        .unwrap();
+    state.pending.remove(i).1
+}
+
+/// Reports a thread re-acquiring the single resource it holds. The state must be unlocked first,
+/// so that the holder's guard can still release the resource as the panic unwinds.
+#[cold]
+fn reentrant_acquire() -> ! {
+    panic!("thread tried to acquire a single resource semaphore it already holds, which would deadlock")
+}
+
+/// Returns the number of CPUs the process may run on, or one if that can't be determined.
+fn available_cpus() -> isize {
+    thread::available_parallelism().map_or(1, |n| isize::try_from(n.get()).unwrap_or(isize::MAX))
+}
+
+/// The tracer acquisitions are reported to, created from the global tracer provider the first time
+/// one is traced.
+#[cfg(feature = "otel")]
+static TRACER: OnceLock<opentelemetry::global::BoxedTracer> = OnceLock::new();
+
+/// Emits a `semaphore.acquire` span covering an acquisition which waited for `waited`, as a child
+/// of the active OpenTelemetry context, with the wait in seconds and the count left afterwards as
+/// attributes.
+///
+/// The tracer is only created once, so a global tracer provider must be installed before the
+/// first acquisition for spans to be recorded.
+#[cfg(feature = "otel")]
+fn trace_acquire(waited: Duration, count: isize) {
+    use opentelemetry::trace::{Span, Tracer};
+    use opentelemetry::KeyValue;
+
+    let tracer = TRACER.get_or_init(|| opentelemetry::global::tracer("TLBoS"));
+    let ended = std::time::SystemTime::now();
+    let mut span = tracer
+        .span_builder("semaphore.acquire")
+        .with_start_time(ended.checked_sub(waited).unwrap_or(ended))
+        .start(tracer);
+    if span.is_recording() {
+        span.set_attribute(KeyValue::new(
+            "semaphore.wait_duration",
+            waited.as_secs_f64(),
+        ));
+        span.set_attribute(KeyValue::new("semaphore.count", count as i64));
+    }
+    span.end_with_timestamp(ended);
+}
+
+/// Unwraps the result of a blocking acquisition, panicking with the reason it failed.
+fn unwrap_acquired<T>(result: Result<T, AcquireError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(AcquireError::Closed) => panic!("acquired from a closed semaphore"),
+        Err(AcquireError::QueueFull) => panic!("acquired from a semaphore with a full wait queue"),
+        Err(AcquireError::Unsatisfiable) => panic!("acquired more than a semaphore's capacity"),
+        Err(AcquireError::Cancelled) => panic!("acquisition cancelled"),
+    }
+}
+
+impl From<isize> for Semaphore {
+    /// Creates a semaphore with the given initial count, as `Semaphore::new`.
+    fn from(n: isize) -> Self {
+        Semaphore::new(n)
+    }
+}
+
+/// The minimal interface shared by semaphore implementations, for code that is generic over which
+/// semaphore it uses.
+pub trait SemaphoreLike {
+    /// Acquires a resource, blocking the current thread until one is available.
+    fn acquire(&self);
+
+    /// Releases a resource, waking a thread blocked in `acquire` if necessary.
+    fn release(&self);
+}
+
+impl<S: PermitStore> SemaphoreLike for Semaphore<S> {
+    fn acquire(&self) {
+        Semaphore::acquire(self)
+    }
+
+    fn release(&self) {
+        Semaphore::release(self)
+    }
+}
+
+// Implement the Drop trait to specify that the SemaphoreGuard should release the semaphore when
+// the guard goes out of scope.
+impl<'a, S: PermitStore> Drop for SemaphoreGuard<'a, S> {
+    fn drop(&mut self) {
+        self.sem.release_n(self.permits)
+    }
+}
+
+// A resource handed to a consumer which gave up before taking it is released as normal, so it
+// isn't lost.
+impl<'a> Drop for HandoffToken<'a> {
+    fn drop(&mut self) {
+        let mut state = self.sem.lock();
+        if let Some(i) = state.handoffs.iter().position(|(id, _)| *id == self.id) {
+            let (_, handoff) = state.handoffs.remove(i);
+            if handoff.granted {
+                self.sem.give_and_unlock(state, 1);
+            }
+        }
+    }
+}
+
+impl<S: PermitStore> Drop for OwnedSemaphoreGuard<S> {
+    fn drop(&mut self) {
+        self.sem.release_n(self.permits)
+    }
+}
+
+// The inner guard is dropped after this runs, so the hold time is reported before the resource is
+// released.
+impl<'a, F: FnOnce(Duration)> Drop for TimedSemaphoreGuard<'a, F> {
+    fn drop(&mut self) {
+        if let Some(on_release) = self.on_release.take() {
+            on_release(self.acquired.elapsed());
+        }
+    }
+}
+
+// The guard holding the resource is a field, so it is only dropped, releasing the resource, once
+// the teardown has run.
+impl<'a, F: FnOnce()> Drop for BundleGuard<'a, F> {
+    fn drop(&mut self) {
+        if let Some(teardown) = self.teardown.take() {
+            teardown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicIsize;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn test_sem_basic() {
+        let s = Semaphore::new(1);
+        let _ = s.access();
+    }
+
+    #[test]
+    fn test_sem_new_capacity() {
+        let sem = Semaphore::new_capacity(2);
+        assert_eq!(sem.available_permits(), 2);
+
+        let sem = Semaphore::new_capacity(isize::MAX as usize);
+        assert_eq!(sem.available_permits(), isize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds isize::MAX")]
+    fn test_sem_new_capacity_too_large() {
+        Semaphore::new_capacity(usize::MAX);
+    }
+
+    #[test]
+    fn test_sem_acquire_release() {
+        let sem = Semaphore::new(1);
+        sem.acquire();
+        sem.release();
+        sem.acquire();
+    }
+
+    #[test]
+    fn test_sem_try_acquire() {
+        let sem = Semaphore::new(1);
+        assert!(sem.try_acquire());
+        assert!(!sem.try_acquire());
+        sem.release();
+        assert!(sem.try_access().is_some());
+    }
+
+    #[test]
+    fn test_sem_try_access_detailed() {
+        let sem = Arc::new(Semaphore::with_max_waiters(1, 1));
+        let guard = sem.try_access_detailed().unwrap();
+        assert_eq!(
+            sem.try_access_detailed().err(),
+            Some(TryAccessError::NoPermits)
+        );
+
+        // Fill the wait queue with a blocked thread.
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire_checked());
+        while sem.waiter_count() < 1 {
+            thread::yield_now();
+        }
+        assert_eq!(
+            sem.try_access_detailed().err(),
+            Some(TryAccessError::QueueFull)
+        );
+        assert_eq!(sem.acquire_checked(), Err(AcquireError::QueueFull));
+
+        drop(guard);
+        assert_eq!(t.join().unwrap(), Ok(()));
+
+        sem.close();
+        assert_eq!(
+            sem.try_access_detailed().err(),
+            Some(TryAccessError::Closed)
+        );
+    }
+
+    #[test]
+    fn test_sem_wait_paths_count_waiters() {
+        let sem = Semaphore::with_max_waiters(0, 1);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                sem.acquire_with(Backoff::Jitter {
+                    min: Duration::from_micros(1),
+                    max: Duration::from_micros(10),
+                })
+            });
+            while sem.waiter_count() < 1 {
+                thread::yield_now();
+            }
+            assert_eq!(sem.contention_count(), 1);
+            assert_eq!(sem.acquire_checked(), Err(AcquireError::QueueFull));
+
+            sem.release();
+            waiter.join().unwrap();
+        });
+        assert_eq!(sem.waiter_count(), 0);
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_try_acquire_nonblocking() {
+        let sem = Arc::new(Semaphore::new(1));
+        let sem2 = sem.clone();
+
+        let (locked_tx, locked_rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+
+        let t = thread::spawn(move || {
+            let _count = sem2.lock_state();
+            locked_tx.send(()).unwrap();
+            let _ = done_rx.recv();
+        });
+
+        locked_rx.recv().unwrap();
+        assert_eq!(sem.try_acquire_nonblocking(), None);
+        done_tx.send(()).unwrap();
+        t.join().unwrap();
+
+        assert_eq!(sem.try_acquire_nonblocking(), Some(true));
+        assert_eq!(sem.try_acquire_nonblocking(), Some(false));
+    }
+
+    #[test]
+    fn test_sem_try_acquire_nonblocking_fast_released() {
+        // A pending fast release is taken directly, without the release's callbacks running.
+        let available = Arc::new(AtomicIsize::new(0));
+        let counter = available.clone();
+        let sem = Semaphore::with_edge_callbacks(
+            0,
+            || {},
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        sem.fast_release();
+        sem.fast_release();
+        assert_eq!(sem.try_acquire_nonblocking(), Some(true));
+        assert_eq!(available.load(Ordering::SeqCst), 0);
+        assert_eq!(sem.fast_releases.load(Ordering::SeqCst), 1);
+
+        // The other is still added to the count by the next lock.
+        assert_eq!(sem.available_permits(), 1);
+        assert_eq!(available.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_with_jitter() {
+        let sem = Arc::new(Semaphore::new(1));
+        let held = Arc::new(AtomicIsize::new(0));
+        let backoff = Backoff::Jitter {
+            min: Duration::from_micros(10),
+            max: Duration::from_micros(200),
+        };
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (sem, held) = (sem.clone(), held.clone());
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        sem.acquire_with(backoff);
+                        assert_eq!(held.fetch_add(1, Ordering::SeqCst), 0);
+                        held.fetch_sub(1, Ordering::SeqCst);
+                        sem.release();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_guard_into_owned() {
+        let sem = Arc::new(Semaphore::new(1));
+        let guard = sem.access();
+        let owned = guard.into_owned(sem.clone());
+        assert_eq!(sem.available_permits(), 0);
+
+        thread::spawn(move || drop(owned)).join().unwrap();
+        assert_eq!(sem.available_permits(), 1);
+
+        let owned = sem.access_owned();
+        assert!(std::ptr::eq(owned.semaphore(), &*sem));
+        let borrowed = owned.into_borrowed(&sem);
+        assert_eq!(sem.available_permits(), 0);
+        drop(borrowed);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "different semaphore")]
+    fn test_sem_guard_into_owned_mismatched() {
+        let (a, b) = (Semaphore::new(1), Arc::new(Semaphore::new(1)));
+        let _ = a.access().into_owned(b);
+    }
+
+    #[test]
+    fn test_sem_guard_release_if() {
+        let sem = Semaphore::with_max(3, 3);
+        assert!(sem.access().release_if(|| true));
+        assert_eq!(sem.available_permits(), 3);
+
+        let unhealthy = sem.access_up_to(2);
+        assert!(!unhealthy.release_if(|| false));
+        assert_eq!(sem.available_permits(), 1);
+        assert_eq!(sem.capacity(), Some(1));
+        assert!(sem.wait_for_idle_timeout(Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn test_sem_guard_release_some() {
+        let sem = Semaphore::new(5);
+        let mut guard = sem.access_up_to(4);
+        assert_eq!(guard.permits_held(), 4);
+
+        guard.release_some(1);
+        assert_eq!(guard.permits_held(), 3);
+        assert_eq!(sem.available_permits(), 2);
+
+        guard.release_some(3);
+        assert_eq!(guard.permits_held(), 0);
+        drop(guard);
+        assert_eq!(sem.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_guard_yield_for() {
+        let sem = Arc::new(Semaphore::new(1));
+        let mut guard = sem.access();
+        let (tx, rx) = channel();
+        let competitor = sem.clone();
+        let t = thread::spawn(move || {
+            let g = competitor.access();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            drop(g);
+        });
+
+        // The competitor acquires the resource while it is yielded, and is holding it when the
+        // yield ends, so the guard waits for it to be given back.
+        let yielded = guard.yield_for(|| {
+            rx.recv().unwrap();
+            "yielded"
+        });
+        assert_eq!(yielded, "yielded");
+        assert_eq!(guard.permits_held(), 1);
+        assert_eq!(sem.available_permits(), 0);
+        t.join().unwrap();
+
+        drop(guard);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_fork() {
+        let parent = Semaphore::new(5);
+        let child = parent.fork(3);
+        assert_eq!(child.reserved(), 3);
+        assert_eq!(parent.available_permits(), 2);
+
+        // The child hands out no more than it reserved.
+        let a = child.access();
+        let b = child.access_up_to(2);
+        assert!(child.try_access().is_none());
+        drop(a);
+        assert!(child.try_access().is_some());
+        drop(b);
+
+        assert!(parent.try_fork(3).is_none());
+        drop(child);
+        assert_eq!(parent.available_permits(), 5);
+        assert!(parent.try_fork(3).is_some());
+    }
+
+    #[test]
+    fn test_sem_fast_release_single_producer() {
+        let sem = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let (sem, tx) = (sem.clone(), tx.clone());
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        sem.acquire();
+                    }
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        let producer = sem.clone();
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                producer.fast_release();
+            }
+        })
+        .join()
+        .unwrap();
+
+        // A lost wakeup would leave a consumer blocked with resources still pending.
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        for c in consumers {
+            c.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_fast_release_visible() {
+        let sem = Semaphore::with_max(2, 2);
+        sem.acquire();
+        sem.fast_release();
+        assert!(sem.wait_for_idle_timeout(Duration::from_secs(1)));
+        sem.fast_release();
+        sem.fast_release();
+        assert_eq!(sem.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_sem_drain_with() {
+        let sem = Semaphore::new(3);
+        let mut calls = 0;
+        sem.drain_with(|_guard| calls += 1);
+        assert_eq!(calls, 3);
+        assert_eq!(sem.available_permits(), 3);
+
+        // Guards kept past the closure stay held.
+        let mut held = Vec::new();
+        sem.drain_with(|guard| held.push(guard));
+        assert_eq!(sem.available_permits(), 0);
+        drop(held);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    /// Publishes a value through the semaphore with `release`, and checks that the thread which
+    /// acquires the resource sees it even though the value itself is only accessed relaxed.
+    fn assert_release_publishes<R>(sem: Semaphore, release: R)
+    where
+        R: Fn(&Semaphore) + Send + Sync + 'static,
+    {
+        let sem = Arc::new(sem);
+        let value = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let release = Arc::new(release);
+
+        for round in 1..=100 {
+            let (sem2, value2, release2) = (sem.clone(), value.clone(), release.clone());
+            let writer = thread::spawn(move || {
+                value2.store(round, Ordering::Relaxed);
+                release2(&sem2);
+            });
+            sem.acquire();
+            assert_eq!(value.load(Ordering::Relaxed), round);
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sem_release_happens_before_acquire() {
+        assert_release_publishes(Semaphore::new(0), |sem| sem.release());
+        assert_release_publishes(Semaphore::new(0), |sem| sem.fast_release());
+        let coalesced = Semaphore::builder(0).coalesce_releases(true).build();
+        assert_release_publishes(coalesced, |sem| sem.release());
+    }
+
+    #[test]
+    fn test_sem_acquire_spin_then_timeout() {
+        // A resource which is already available is taken while spinning.
+        let sem = Arc::new(Semaphore::new(1));
+        assert!(sem.acquire_spin_then_timeout(10, Duration::from_secs(0)));
+        assert_eq!(sem.contention_count(), 0);
+
+        // One released while blocked is taken before the timeout.
+        let releaser = sem.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            releaser.release();
+        });
+        assert!(sem.acquire_spin_then_timeout(10, Duration::from_secs(10)));
+        assert_eq!(sem.contention_count(), 1);
+        t.join().unwrap();
+
+        // Otherwise the timeout elapses.
+        let started = Instant::now();
+        assert!(!sem.acquire_spin_then_timeout(10, Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // It fails straight away if no more threads may wait.
+        let full = Semaphore::with_max_waiters(0, 0);
+        assert!(!full.acquire_spin_then_timeout(10, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_sem_most_available() {
+        let (a, b, c) = (Semaphore::new(2), Semaphore::new(5), Semaphore::new(5));
+        let _g = a.access();
+        let best = Semaphore::most_available(&[&a, &b, &c]);
+        assert!(std::ptr::eq(best, &b));
+
+        let _g = b.access();
+        let best = Semaphore::most_available(&[&a, &b, &c]);
+        assert!(std::ptr::eq(best, &c));
+    }
+
+    #[test]
+    fn test_sem_render_prometheus() {
+        let sem = Semaphore::with_metrics_name(3, "db_pool");
+        let a = sem.access();
+        let b = sem.access_up_to(2);
+        drop(a);
+        sem.fast_release();
+
+        let out = sem.render_prometheus();
+        assert!(out.contains("# TYPE db_pool_available gauge\ndb_pool_available 2\n"));
+        assert!(out.contains("# TYPE db_pool_waiters gauge\ndb_pool_waiters 0\n"));
+        assert!(out.contains("# TYPE db_pool_acquired_total counter\ndb_pool_acquired_total 3\n"));
+        assert!(out.contains("# TYPE db_pool_released_total counter\ndb_pool_released_total 2\n"));
+
+        drop(b);
+        let out = Semaphore::new(1).render_prometheus();
+        assert!(out.contains("semaphore_available 1\n"));
+        assert!(!out.contains("_total"));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "already holds")]
+    fn test_sem_reentrant_acquire() {
+        let sem = Semaphore::new(1);
+        let _g = sem.access();
+        sem.acquire();
+    }
+
+    #[test]
+    fn test_sem_reacquire_owned_elsewhere() {
+        // Without the reentrancy check, a thread may wait for the resource it took once its guard
+        // has been handed to another thread.
+        let sem = Arc::new(Semaphore::new(1).for_signalling());
+        let guard = sem.access_owned();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                drop(guard);
+            });
+            sem.acquire();
+        });
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_reacquire_opted_out() {
+        let sem = Arc::new(
+            Semaphore::builder(1)
+                .detect_reentrant_acquire(false)
+                .build(),
+        );
+        sem.acquire();
+        let releaser = sem.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            releaser.release();
+        });
+        sem.acquire();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_reacquire_after_handoff() {
+        // Another thread releasing the resource clears the holder.
+        let sem = Arc::new(Semaphore::new(1));
+        sem.acquire();
+        let releaser = sem.clone();
+        thread::spawn(move || releaser.release()).join().unwrap();
+        sem.acquire();
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_release_all() {
+        let a = Arc::new(Semaphore::new(3));
+        let b = Arc::new(Semaphore::new(1));
+
+        let mut guards = a.access_many_owned(3);
+        guards.push(b.access_owned());
+        assert_eq!(a.available_permits(), 0);
+        assert_eq!(b.available_permits(), 0);
+
+        Semaphore::release_all(guards);
+        assert_eq!(a.available_permits(), 3);
+        assert_eq!(b.available_permits(), 1);
+
+        // Owned guards can be kept alongside other resources which are released at the same time.
+        let phase: Vec<Box<dyn Send>> = vec![Box::new(a.access_owned()), Box::new(vec![1, 2])];
+        assert_eq!(a.available_permits(), 2);
+        drop(phase);
+        assert_eq!(a.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_notify_after_unlock() {
+        let sem = Arc::new(Semaphore::builder(0).notify_after_unlock(true).build());
+        let (tx, rx) = channel();
+
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let (sem, tx) = (sem.clone(), tx.clone());
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        sem.acquire();
+                    }
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+        for _ in 0..1000 {
+            sem.release();
+        }
+
+        // A lost wakeup would leave a consumer blocked with resources still available.
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        for c in consumers {
+            c.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_coalesce_releases() {
+        let sem = Arc::new(Semaphore::builder(0).coalesce_releases(true).build());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        sem.release();
+                    }
+                    sem.release_n(10);
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 8 * 1010);
+    }
+
+    #[test]
+    fn test_sem_coalesce_releases_wakes_waiters() {
+        let sem = Arc::new(Semaphore::builder(0).coalesce_releases(true).build());
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        sem.acquire();
+                    }
+                })
+            })
+            .collect();
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        sem.release();
+                    }
+                })
+            })
+            .collect();
+        for h in producers.into_iter().chain(consumers) {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_checked_n() {
+        let sem = Arc::new(Semaphore::with_max(3, 3));
+        assert_eq!(
+            sem.acquire_checked_n(4).err(),
+            Some(AcquireError::Unsatisfiable)
+        );
+
+        let guard = sem.acquire_checked_n(2).unwrap();
+        assert_eq!(sem.available_permits(), 1);
+
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire_checked_n(3).map(|g| g.permits));
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert_eq!(t.join().unwrap(), Ok(3));
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_acquire_checked_n_queue_full() {
+        let sem = Semaphore::with_max_waiters(1, 1);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| sem.acquire_checked_n(2).map(|g| g.permits));
+            while sem.waiter_count() < 1 {
+                thread::yield_now();
+            }
+            assert_eq!(
+                sem.acquire_checked_n(2).err(),
+                Some(AcquireError::QueueFull)
+            );
+            sem.release();
+            assert_eq!(waiter.join().unwrap(), Ok(2));
+        });
+        assert_eq!(sem.waiter_count(), 0);
+    }
+
+    /// Queues `n` waiters on a semaphore one after another, then releases one resource at a time
+    /// and returns the order the waiters were served in.
+    fn served_order(sem: Semaphore, n: usize) -> Vec<usize> {
+        let sem = Arc::new(sem);
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..n)
+            .map(|i| {
+                let (waiter, tx) = (sem.clone(), tx.clone());
+                let handle = thread::spawn(move || {
+                    waiter.acquire();
+                    tx.send(i).unwrap();
+                });
+                while sem.lock_state().queue.len() < i + 1 {
+                    thread::yield_now();
+                }
+                handle
+            })
+            .collect();
+
+        let order = (0..n)
+            .map(|_| {
+                sem.release();
+                rx.recv().unwrap()
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        order
+    }
+
+    #[test]
+    fn test_sem_ordering() {
+        let fifo = Semaphore::builder(0).ordering(WaiterOrder::Fifo).build();
+        assert_eq!(served_order(fifo, 3), vec![0, 1, 2]);
+
+        let lifo = Semaphore::builder(0).ordering(WaiterOrder::Lifo).build();
+        assert_eq!(served_order(lifo, 3), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_sem_builder() {
+        let sem = Semaphore::builder(1)
+            .fair(true)
+            .max(2)
+            .max_waiters(4)
+            .build();
+        assert!(sem.fair);
+        assert_eq!(sem.capacity(), Some(2));
+        sem.release_n(5);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_start_acquire_dropped() {
+        let sem = Semaphore::new(0);
+        let pending = sem.start_acquire();
+        assert_eq!(sem.lock_state().queue.len(), 1);
+        drop(pending);
+        assert!(sem.lock_state().queue.is_empty());
+
+        sem.release();
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_start_acquire_complete() {
+        let sem = Arc::new(Semaphore::fair(0));
+        let pending = sem.start_acquire();
+
+        // Queued acquisitions that start later are served after the pending one.
+        let (tx, rx) = channel();
+        let later = sem.clone();
+        let t = thread::spawn(move || {
+            later.acquire();
+            tx.send(()).unwrap();
+        });
+        while sem.lock_state().queue.len() < 2 {
+            thread::yield_now();
+        }
+
+        sem.release();
+        let guard = pending.complete();
+        assert!(rx.try_recv().is_err());
+        drop(guard);
+        rx.recv().unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_start_acquire_granted() {
+        let sem = Semaphore::fair(1);
+        let held = sem.access();
+        let pending = sem.start_acquire();
+
+        // The released resource goes to the pending acquisition, which then no longer holds up
+        // the rest of the queue.
+        drop(held);
+        assert!(sem.lock_state().queue.is_empty());
+        assert!(!sem.try_acquire());
+        sem.add_permits(1);
+        assert!(sem.try_acquire());
+
+        let guard = pending.complete();
+        drop(guard);
+        assert_eq!(sem.available_permits(), 1);
+
+        // A pending acquisition dropped after being handed a resource gives it back.
+        drop(sem.start_acquire());
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_priority_aging() {
+        let sem = Arc::new(Semaphore::with_priority_aging(0, 100.0));
+        let (tx, rx) = channel();
+
+        let wait_for_queued = |n| {
+            while sem.lock_state().queue.len() < n {
+                thread::yield_now();
+            }
+        };
+
+        let (low_sem, low_tx) = (sem.clone(), tx.clone());
+        let low = thread::spawn(move || {
+            low_sem.acquire_priority(0);
+            low_tx.send("low").unwrap();
+        });
+        wait_for_queued(1);
+
+        // Long enough for the low priority waiter to age past the high priority one.
+        thread::sleep(Duration::from_millis(200));
+
+        let (high_sem, high_tx) = (sem.clone(), tx);
+        let high = thread::spawn(move || {
+            high_sem.acquire_priority(10);
+            high_tx.send("high").unwrap();
+        });
+        wait_for_queued(2);
+
+        sem.release();
+        assert_eq!(rx.recv().unwrap(), "low");
+        sem.release();
+        assert_eq!(rx.recv().unwrap(), "high");
+
+        low.join().unwrap();
+        high.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_priority_order() {
+        let sem = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+
+        let handles: Vec<_> = [1, 5, 3]
+            .iter()
+            .enumerate()
+            .map(|(i, &priority)| {
+                let (waiter, tx) = (sem.clone(), tx.clone());
+                let t = thread::spawn(move || {
+                    waiter.acquire_priority(priority);
+                    tx.send(priority).unwrap();
+                });
+                while sem.lock_state().queue.len() <= i {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        for expected in [5, 3, 1].iter() {
+            sem.release();
+            assert_eq!(rx.recv().unwrap(), *expected);
+        }
+
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sem_access_many_owned() {
+        let sem = Arc::new(Semaphore::new(3));
+        let (tx, rx) = channel();
+
+        let handles: Vec<_> = sem
+            .access_many_owned(3)
+            .into_iter()
+            .map(|guard| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let _guard = guard;
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(rx.iter().take(3).count(), 3);
+        assert_eq!(sem.try_access_many_owned(5).len(), 3);
+    }
+
+    #[test]
+    fn test_sem_try_access_many_owned_partial() {
+        let sem = Arc::new(Semaphore::new(2));
+        let guards = sem.try_access_many_owned(3);
+        assert_eq!(guards.len(), 2);
+        assert!(!sem.try_acquire());
+        drop(guards);
+        assert_eq!(sem.try_access_many_owned(2).len(), 2);
+    }
+
+    #[test]
+    fn test_sem_stress_spurious_wakeups() {
+        const THREADS: usize = 16;
+        const ITERATIONS: usize = 500;
+
+        let sem = Arc::new(Semaphore::new(2));
+        let in_use = Arc::new(AtomicIsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Wake every waiter as often as possible without releasing anything, so waiters have to
+        // re-check the count and go back to sleep.
+        let spurious = {
+            let (sem, done) = (sem.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    sem.condvar.notify_all();
+                    thread::yield_now();
+                }
+            })
+        };
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (sem, in_use) = (sem.clone(), in_use.clone());
+                thread::spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        let _g = sem.access();
+                        let held = in_use.fetch_add(1, Ordering::SeqCst);
+                        assert!(held < 2);
+                        in_use.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        spurious.join().unwrap();
+
+        assert_eq!(sem.lock_state().count, 2);
+    }
+
+    fn edge_counting_semaphore(n: isize) -> (Arc<Semaphore>, Arc<AtomicIsize>, Arc<AtomicIsize>) {
+        let exhausted = Arc::new(AtomicIsize::new(0));
+        let available = Arc::new(AtomicIsize::new(0));
+        let (e, a) = (exhausted.clone(), available.clone());
+        let sem = Semaphore::with_edge_callbacks(
+            n,
+            move || {
+                e.fetch_add(1, Ordering::SeqCst);
+            },
+            move || {
+                a.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        (Arc::new(sem), exhausted, available)
+    }
+
+    #[test]
+    fn test_sem_edge_callbacks() {
+        let (sem, exhausted, available) = edge_counting_semaphore(2);
+
+        sem.acquire();
+        assert_eq!(exhausted.load(Ordering::SeqCst), 0);
+        sem.acquire();
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+        assert!(!sem.try_acquire());
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+
+        sem.release();
+        assert_eq!(available.load(Ordering::SeqCst), 1);
+        sem.release();
+        assert_eq!(available.load(Ordering::SeqCst), 1);
+        sem.acquire();
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_sem_edge_callbacks_concurrent() {
+        let (sem, exhausted, available) = edge_counting_semaphore(1);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let _g = sem.access();
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        // With a single resource every acquisition exhausts the semaphore and every release
+        // makes it available again.
+        assert_eq!(exhausted.load(Ordering::SeqCst), 400);
+        assert_eq!(available.load(Ordering::SeqCst), 400);
+    }
+
+    fn use_it<S: SemaphoreLike>(s: &S) {
+        s.acquire();
+        s.release();
+        s.acquire();
+    }
+
+    #[test]
+    fn test_sem_like() {
+        let sem = Semaphore::new(1);
+        use_it(&sem);
+        assert!(!sem.try_acquire());
+    }
+
+    #[test]
+    fn test_sem_extreme_counts() {
+        let sem = Semaphore::new(isize::MIN);
+        assert!(!sem.try_acquire());
+        assert_eq!(sem.try_acquire_nonblocking(), Some(false));
+        sem.release();
+        assert_eq!(sem.lock_state().count, isize::MIN + 1);
+
+        let sem = Semaphore::new(isize::MAX);
+        sem.release();
+        assert_eq!(sem.lock_state().count, isize::MAX);
+        assert!(sem.try_acquire());
+        assert_eq!(sem.lock_state().count, isize::MAX - 1);
+    }
+
+    #[test]
+    fn test_sem_access_timed() {
+        let sem = Semaphore::new(1);
+        let (tx, rx) = channel();
+
+        {
+            let _g = sem.access_timed(|held| tx.send(held).unwrap());
+            assert!(!sem.try_acquire());
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let held = rx.recv().unwrap();
+        assert!(held >= Duration::from_millis(50));
+        assert!(held < Duration::from_secs(5));
+        assert!(sem.try_acquire());
+    }
+
+    #[test]
+    fn test_sem_access_bundle() {
+        let sem = Arc::new(Semaphore::new(1));
+        let torn_down = Arc::new(AtomicBool::new(false));
+        let g = {
+            let torn_down = torn_down.clone();
+            sem.access_bundle(move || {
+                thread::sleep(Duration::from_millis(20));
+                torn_down.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let (sem2, torn_down2) = (sem.clone(), torn_down.clone());
+        let t = thread::spawn(move || {
+            sem2.acquire();
+            torn_down2.load(Ordering::SeqCst)
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(g);
+        assert!(t.join().unwrap());
+    }
+
+    #[test]
+    fn test_sem_try_acquire_all() {
+        let sem = Semaphore::new_capacity(3);
+        let g = sem.try_acquire_all().unwrap();
+        assert_eq!(g.permits_held(), 3);
+        assert_eq!(sem.available_permits(), 0);
+        assert!(sem.try_acquire_all().is_none());
+        drop(g);
+        assert_eq!(sem.available_permits(), 3);
+
+        let _one = sem.access();
+        assert!(sem.try_acquire_all().is_none());
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_acquire_handle() {
+        let sem = Semaphore::new(1);
+
+        let token = sem.acquire_handle();
+        token.cancel();
+        assert_eq!(sem.lock_state().count, 1);
+
+        assert!(sem.acquire_handle().wait());
+        assert_eq!(sem.lock_state().count, 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_handle_cancel_while_waiting() {
+        let sem = Semaphore::new(0);
+        let token = sem.acquire_handle();
+        let canceller = token.canceller();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                canceller.cancel();
+            });
+            assert!(!token.wait());
+        });
+
+        sem.release();
+        assert_eq!(sem.lock_state().count, 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_handle_cancel_blocked() {
+        let sem = Semaphore::new(0);
+        let token = sem.acquire_handle();
+
+        thread::scope(|scope| {
+            let token = &token;
+            let waiter = scope.spawn(move || token.wait_checked());
+            while sem.waiter_count() == 0 {
+                thread::yield_now();
+            }
+            token.cancel();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Cancelled));
+        });
+
+        sem.release();
+        assert_eq!(sem.lock_state().count, 1);
+    }
+
+    #[test]
+    fn test_sem_release_to() {
+        let sem = Semaphore::new(0);
+        let (tx, rx) = channel();
+        thread::scope(|scope| {
+            let sem = &sem;
+            let (a, b) = (sem.register_handoff(), sem.register_handoff());
+            let (a_ticket, b_ticket) = (a.ticket(), b.ticket());
+            let (a_tx, b_tx, plain_tx) = (tx.clone(), tx.clone(), tx.clone());
+            let a = scope.spawn(move || {
+                let guard = a.wait();
+                a_tx.send("a").unwrap();
+                guard
+            });
+            let b = scope.spawn(move || {
+                let guard = b.wait();
+                b_tx.send("b").unwrap();
+                guard
+            });
+            let plain = scope.spawn(move || {
+                sem.acquire();
+                plain_tx.send("plain").unwrap();
+            });
+            thread::sleep(Duration::from_millis(20));
+
+            // Only the consumer the resource is handed to wakes, with others waiting too.
+            sem.release_to(b_ticket);
+            assert_eq!(rx.recv().unwrap(), "b");
+            thread::sleep(Duration::from_millis(20));
+            assert!(rx.try_recv().is_err());
+            let b_guard = b.join().unwrap();
+            assert_eq!(sem.available_permits(), 0);
+
+            sem.release_to(a_ticket);
+            assert_eq!(rx.recv().unwrap(), "a");
+
+            // A handed over resource is released as normal, here to the other waiter.
+            drop(b_guard);
+            assert_eq!(rx.recv().unwrap(), "plain");
+            plain.join().unwrap();
+            drop(a.join().unwrap());
+        });
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_release_to_abandoned() {
+        let sem = Semaphore::new(0);
+        let token = sem.register_handoff();
+        let ticket = token.ticket();
+        drop(token);
+        sem.release_to(ticket);
+        assert_eq!(sem.available_permits(), 1);
+
+        // A resource handed over but never taken is released once the registration is given up.
+        let token = sem.register_handoff();
+        sem.release_to(token.ticket());
+        assert_eq!(sem.available_permits(), 1);
+        drop(token);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_release_n() {
+        let looped = Semaphore::new(-2);
+        for _ in 0..5 {
+            looped.release();
+        }
+        let batched = Semaphore::new(-2);
+        batched.release_n(5);
+        assert_eq!(batched.lock_state().count, looped.lock_state().count);
+
+        batched.release_n(0);
+        assert_eq!(batched.lock_state().count, 3);
+    }
+
+    #[test]
+    fn test_sem_release_n_wakes_all() {
+        let sem = Arc::new(Semaphore::new(0));
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || sem.acquire())
+            })
+            .collect();
+
+        sem.release_n(3);
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.lock_state().count, 0);
+    }
+
+    #[test]
+    fn test_sem_capacity() {
+        let capped = Semaphore::with_max(2, 4);
+        assert_eq!(capped.capacity(), Some(4));
+        assert_eq!(capped.available_permits(), 2);
+
+        capped.release_n(5);
+        assert_eq!(capped.available_permits(), 4);
+        capped.release();
+        assert_eq!(capped.available_permits(), 4);
+
+        let uncapped = Semaphore::new(2);
+        assert_eq!(uncapped.capacity(), None);
+        uncapped.release_n(5);
+        assert_eq!(uncapped.available_permits(), 7);
+    }
+
+    #[test]
+    fn test_sem_contention_count() {
+        let sem = Arc::new(Semaphore::new(2));
+        sem.acquire();
+        sem.acquire();
+        assert_eq!(sem.contention_count(), 0);
+
+        let sem2 = sem.clone();
+        let t = thread::spawn(move || sem2.acquire());
+        while sem.contention_count() == 0 {
+            thread::yield_now();
+        }
+        sem.release();
+        t.join().unwrap();
+
+        assert_eq!(sem.contention_count(), 1);
+        sem.release();
+        sem.acquire();
+        assert_eq!(sem.contention_count(), 1);
+    }
+
+    #[test]
+    fn test_sem_checkpoint_consistent() {
+        let sem = Arc::new(Semaphore::with_max(3, 3));
+        let done = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let (sem, done) = (sem.clone(), done.clone());
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        let _g = sem.acquire_checked_n(1 + i % 2).unwrap();
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..1000 {
+            let snapshot = sem.checkpoint();
+            assert_eq!(snapshot.capacity, Some(3));
+            assert_eq!(snapshot.available + snapshot.outstanding, 3);
+            assert!(snapshot.available >= 0 && snapshot.waiters <= 4);
+        }
+        done.store(true, Ordering::Relaxed);
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let snapshot = sem.checkpoint();
+        assert_eq!((snapshot.available, snapshot.outstanding), (3, 0));
+        assert_eq!(snapshot.waiters, 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_all() {
+        let (a, b) = (Arc::new(Semaphore::new(1)), Arc::new(Semaphore::new(1)));
+
+        // Acquiring in opposite orders from two threads would deadlock without a global order.
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let (a, b) = (a.clone(), b.clone());
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let sems: [&Semaphore; 2] = if i == 0 { [&a, &b] } else { [&b, &a] };
+                        let guards = Semaphore::acquire_all(&sems);
+                        assert_eq!(guards.len(), 2);
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(a.available_permits(), 1);
+        assert_eq!(b.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_fair() {
+        let sem = Arc::new(Semaphore::fair(0));
+        assert_eq!(sem.next_waiter_ticket(), None);
+
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..2u64)
+            .map(|i| {
+                let (waiter, tx) = (sem.clone(), tx.clone());
+                let t = thread::spawn(move || {
+                    waiter.acquire();
+                    tx.send(i).unwrap();
+                });
+                while sem.lock_state().queue.len() <= i as usize {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        assert_eq!(sem.next_waiter_ticket(), Some(0));
+        sem.release();
+        assert_eq!(rx.recv().unwrap(), 0);
+        assert_eq!(sem.next_waiter_ticket(), Some(1));
+
+        // A fair semaphore doesn't let newcomers take resources ahead of queued threads.
+        sem.release();
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(sem.next_waiter_ticket(), None);
+
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sem_fair_try_acquire() {
+        let sem = Arc::new(Semaphore::fair(0));
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire());
+        while sem.next_waiter_ticket().is_none() {
+            thread::yield_now();
+        }
+
+        // Hold the lock across the release and the check, so the queued thread can't run in
+        // between.
+        {
+            let mut state = sem.lock_state();
+            sem.give(&mut state, 1);
+            assert!(!sem.can_take_unqueued(&state, 1));
+        }
+        t.join().unwrap();
+
+        sem.release();
+        assert!(sem.try_acquire());
+    }
+
+    #[test]
+    fn test_sem_acquire_dynamic() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.acquire_dynamic(|| 2), 2);
+        assert_eq!(sem.available_permits(), 1);
+        assert_eq!(sem.acquire_dynamic(|| 0), 0);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_dynamic_changing_cost() {
+        let sem = Arc::new(Semaphore::new(2));
+        let attempts = Arc::new(AtomicIsize::new(0));
+
+        let (waiter, counter) = (sem.clone(), attempts.clone());
+        let t = thread::spawn(move || {
+            // Asks for more than is available at first, then settles for 2.
+            waiter.acquire_dynamic(|| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    3
+                } else {
+                    2
+                }
+            })
+        });
+        while attempts.load(Ordering::SeqCst) == 0 || sem.contention_count() == 0 {
+            thread::yield_now();
+        }
+
+        sem.release();
+        assert_eq!(t.join().unwrap(), 2);
+        assert!(attempts.load(Ordering::SeqCst) >= 2);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_acquire_dynamic_waiters() {
+        let sem = Semaphore::with_max_waiters(0, 1);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| sem.acquire_dynamic(|| 2));
+            while sem.waiter_count() < 1 {
+                thread::yield_now();
+            }
+            assert_eq!(sem.acquire_checked(), Err(AcquireError::QueueFull));
+            sem.release_n(2);
+            assert_eq!(waiter.join().unwrap(), 2);
+        });
+        assert_eq!(sem.waiter_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "full wait queue")]
+    fn test_sem_acquire_dynamic_queue_full() {
+        Semaphore::with_max_waiters(0, 0).acquire_dynamic(|| 1);
+    }
+
+    #[test]
+    fn test_sem_from() {
+        assert_eq!(Semaphore::from(3).available_permits(), 3);
+        let sem: Semaphore = 4.into();
+        assert_eq!(sem.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_sem_close() {
+        let sem = Arc::new(Semaphore::fair(0));
+        let (plain, queued) = (sem.clone(), sem.clone());
+        let t1 = thread::spawn(move || plain.acquire_checked());
+        let t2 = thread::spawn(move || queued.acquire_queued(0));
+        while sem.next_waiter_ticket().is_none() {
+            thread::yield_now();
+        }
+
+        sem.close();
+        assert!(sem.is_closed());
+        assert_eq!(t1.join().unwrap(), Err(AcquireError::Closed));
+        assert_eq!(t2.join().unwrap(), Err(AcquireError::Closed));
+        assert_eq!(sem.next_waiter_ticket(), None);
+
+        sem.release();
+        assert!(!sem.try_acquire());
+        assert!(sem.access_checked().is_err());
+        assert!(!sem.acquire_handle().wait());
+    }
+
+    #[test]
+    fn test_sem_close_with() {
+        let sem = Semaphore::new(4);
+        let (a, b) = (sem.access(), sem.access_up_to(2));
+        let orphans = Arc::new(AtomicIsize::new(0));
+        let counter = orphans.clone();
+        sem.close_with(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(orphans.load(Ordering::SeqCst), 0);
+
+        drop(a);
+        assert_eq!(orphans.load(Ordering::SeqCst), 1);
+        drop(b);
+        assert_eq!(orphans.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_sem_close_with_add_permits() {
+        let sem = Semaphore::new(0);
+        let orphans = Arc::new(AtomicIsize::new(0));
+        let counter = orphans.clone();
+        sem.close_with(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        sem.add_permits(2);
+        assert_eq!(orphans.load(Ordering::SeqCst), 0);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_close_with_racing_release() {
+        // A released resource is either handed to the waiter or torn down, never both. Torn down
+        // resources are still counted, as are those released just too late for the waiter.
+        for _ in 0..100 {
+            let sem = Arc::new(Semaphore::new(1));
+            let guard = sem.access_owned();
+            let waiter = {
+                let sem = sem.clone();
+                thread::spawn(move || sem.acquire_checked().is_ok())
+            };
+            while sem.waiter_count() == 0 {
+                thread::yield_now();
+            }
+            let orphans = Arc::new(AtomicIsize::new(0));
+            let start = Arc::new(Barrier::new(2));
+            let closer = {
+                let (sem, counter, start) = (sem.clone(), orphans.clone(), start.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    sem.close_with(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+            };
+            start.wait();
+            drop(guard);
+            closer.join().unwrap();
+            let acquired = waiter.join().unwrap();
+            assert!(!acquired || orphans.load(Ordering::SeqCst) == 0);
+            assert_eq!(acquired as isize + sem.available_permits(), 1);
+        }
+    }
+
+    #[test]
+    fn test_sem_release_after_close() {
+        let sem = Semaphore::new(2);
+        let g1 = sem.access();
+        let g2 = sem.access();
+
+        sem.close();
+        drop(g1);
+        drop(g2);
+
+        // Released resources are still accounted for, but can't be acquired.
+        assert_eq!(sem.available_permits(), 2);
+        assert!(sem.try_access().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "acquired from a closed semaphore")]
+    fn test_sem_acquire_closed() {
+        let sem = Semaphore::new(1);
+        sem.close();
+        sem.acquire();
+    }
+
+    #[test]
+    fn test_sem_wait_for_idle() {
+        let sem = Arc::new(Semaphore::new(2));
+        assert!(sem.wait_for_idle_timeout(Duration::from_millis(0)));
+
+        let handles: Vec<_> = sem
+            .access_many_owned(2)
+            .into_iter()
+            .map(|guard| {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    drop(guard);
+                })
+            })
+            .collect();
+
+        sem.close();
+        sem.wait_for_idle();
+        assert_eq!(sem.available_permits(), 2);
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sem_wait_for_idle_timeout() {
+        let sem = Arc::new(Semaphore::with_max(2, 2));
+
+        let guard = sem.access_owned();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+        });
+        assert!(sem.wait_for_idle_timeout(Duration::from_secs(5)));
+        t.join().unwrap();
+
+        let _stuck = sem.access();
+        assert!(!sem.wait_for_idle_timeout(Duration::from_millis(20)));
+    }
+
+    #[derive(Default)]
+    struct CountingObserver {
+        acquired: AtomicIsize,
+        released: AtomicIsize,
+    }
+
+    impl SemaphoreObserver for CountingObserver {
+        fn on_acquire(&self, n: isize, _waited: Duration) {
+            self.acquired.fetch_add(n, Ordering::SeqCst);
+        }
+
+        fn on_release(&self, n: isize) {
+            self.released.fetch_add(n, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_sem_observer() {
+        let first = Arc::new(CountingObserver::default());
+        let sem = Semaphore::with_observer(2, first.clone());
+
+        drop(sem.access());
+        assert!(sem.try_acquire());
+        sem.release();
+        assert_eq!(first.acquired.load(Ordering::SeqCst), 2);
+        assert_eq!(first.released.load(Ordering::SeqCst), 2);
+
+        let second = Arc::new(CountingObserver::default());
+        sem.set_observer(Some(second.clone()));
+        assert_eq!(sem.acquire_dynamic(|| 2), 2);
+        sem.release_n(2);
+        assert_eq!(first.acquired.load(Ordering::SeqCst), 2);
+        assert_eq!(second.acquired.load(Ordering::SeqCst), 2);
+        assert_eq!(second.released.load(Ordering::SeqCst), 2);
+
+        sem.set_observer(None);
+        drop(sem.access());
+        assert_eq!(second.acquired.load(Ordering::SeqCst), 2);
+    }
+
+    #[derive(Default)]
+    struct WaitObserver {
+        waited: Mutex<Option<Duration>>,
+    }
+
+    impl SemaphoreObserver for WaitObserver {
+        fn on_acquire(&self, _n: isize, waited: Duration) {
+            *self.waited.lock().unwrap() = Some(waited);
+        }
+    }
+
+    #[test]
+    fn test_sem_acquire_from() {
+        let observer = Arc::new(WaitObserver::default());
+        let sem = Semaphore::with_observer(2, observer.clone());
+
+        let enqueued_at = Instant::now();
+        thread::sleep(Duration::from_millis(30));
+        sem.acquire();
+        assert!(observer.waited.lock().unwrap().unwrap() < Duration::from_millis(30));
+
+        sem.acquire_from(enqueued_at);
+        assert!(observer.waited.lock().unwrap().unwrap() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_sem_observer_swap_concurrent() {
+        let first = Arc::new(CountingObserver::default());
+        let sem = Arc::new(Semaphore::with_observer(2, first.clone()));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        drop(sem.access());
+                    }
+                })
+            })
+            .collect();
+
+        let second = Arc::new(CountingObserver::default());
+        sem.set_observer(Some(second.clone()));
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        // Every event went to exactly one of the observers.
+        let acquired =
+            first.acquired.load(Ordering::SeqCst) + second.acquired.load(Ordering::SeqCst);
+        let released =
+            first.released.load(Ordering::SeqCst) + second.released.load(Ordering::SeqCst);
+        assert_eq!(acquired, 800);
+        assert_eq!(released, 800);
+
+        // Events after the swap go to the new observer.
+        let before = (
+            first.acquired.load(Ordering::SeqCst),
+            second.acquired.load(Ordering::SeqCst),
+        );
+        drop(sem.access());
+        assert_eq!(first.acquired.load(Ordering::SeqCst), before.0);
+        assert_eq!(second.acquired.load(Ordering::SeqCst), before.1 + 1);
+    }
+
+    #[test]
+    fn test_sem_subscribe() {
+        let sem = Semaphore::new(0);
+        let a = sem.subscribe();
+        let b = sem.subscribe();
+        assert!(a.try_recv().is_err());
+
+        sem.release();
+        a.recv_timeout(Duration::from_secs(1)).unwrap();
+        b.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(sem.available_permits(), 1);
+
+        // Unread notifications are coalesced rather than buffered.
+        sem.release();
+        sem.release();
+        assert!(a.try_recv().is_ok());
+        assert!(a.try_recv().is_err());
+
+        // Dropped receivers are unsubscribed on the next notification.
+        drop(b);
+        sem.release();
+        assert_eq!(sem.lock_state().subscribers.len(), 1);
+    }
+
+    #[cfg(feature = "crossbeam")]
+    #[test]
+    fn test_sem_subscribe_crossbeam() {
+        use crossbeam_channel::{select, unbounded};
+
+        let sem = Arc::new(Semaphore::new(1));
+        let ready = sem.subscribe_crossbeam();
+        let (tx, rx) = unbounded();
+
+        // Both branches are ready, and whichever wins, selecting consumed no resource.
+        tx.send(7).unwrap();
+        select! {
+            recv(rx) -> msg => assert_eq!(msg, Ok(7)),
+            recv(ready) -> msg => msg.unwrap(),
+        }
+        assert_eq!(sem.available_permits(), 1);
+
+        // A release wakes a select waiting on an exhausted semaphore.
+        let held = sem.access_owned();
+        while ready.try_recv().is_ok() || rx.try_recv().is_ok() {}
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        select! {
+            recv(rx) -> _ => panic!("no message was sent"),
+            recv(ready) -> msg => msg.unwrap(),
+        }
+        releaser.join().unwrap();
+        assert!(sem.try_access().is_some());
+    }
+
+    #[test]
+    fn test_sem_add_permits_forget() {
+        let sem = Semaphore::with_max(2, 2);
+        sem.add_permits(2);
+        assert_eq!(sem.capacity(), Some(4));
+        assert_eq!(sem.available_permits(), 4);
+
+        let _g = sem.access();
+        assert_eq!(sem.forget(5), 3);
+        assert_eq!(sem.capacity(), Some(1));
+        assert_eq!(sem.available_permits(), 0);
+        assert!(!sem.wait_for_idle_timeout(Duration::from_millis(0)));
+
+        drop(_g);
+        assert!(sem.wait_for_idle_timeout(Duration::from_millis(0)));
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_min_floor() {
+        let sem = Semaphore::with_min(4, 2);
+        assert_eq!(sem.forget(3), 2);
+        assert_eq!(sem.forget(1), 0);
+        assert_eq!(sem.available_permits(), 2);
+
+        let sem = Semaphore::builder(4).max(4).min(3).build();
+        sem.shrink_to(0);
+        assert_eq!(sem.capacity(), Some(3));
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "initial count is below the minimum")]
+    fn test_sem_min_above_count() {
+        Semaphore::with_min(1, 2);
+    }
+
+    #[test]
+    fn test_sem_access_array() {
+        let sem = Arc::new(Semaphore::new(4));
+        let guards = sem.access_array::<3>();
+        assert_eq!(guards.len(), 3);
+        assert!(guards.iter().all(|g| g.permits_held() == 1));
+        assert_eq!(sem.available_permits(), 1);
+
+        let _last = sem.access();
+        let (tx, rx) = channel();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || {
+            let _g = waiter.access();
+            tx.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        let [a, _, _] = guards;
+        drop(a);
+        rx.recv().unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_shrink_to() {
+        let sem = Arc::new(Semaphore::with_max(4, 4));
+        let a = sem.access();
+        let b = sem.access();
+
+        let (tx, rx) = channel();
+        let shrinker = sem.clone();
+        let t = thread::spawn(move || {
+            shrinker.shrink_to(2);
+            tx.send(()).unwrap();
+        });
+
+        // The available resources are reclaimed at once, but the held ones are not revoked.
+        while sem.capacity() != Some(2) {
+            thread::yield_now();
+        }
+        assert_eq!(sem.available_permits(), 0);
+        drop(a);
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        drop(b);
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(sem.available_permits(), 2);
+        assert_eq!(sem.capacity(), Some(2));
+    }
 
-impl Semaphore {
-    /// Initialize a new semaphore with the initial count specified.
-    ///
-    /// The count can be thought of as the number of resources that the semaphore is protecting.
-    /// A call to `acquire` or `access` will block until at least one resource is available. It is
-    /// valid to initialize a semaphore with a negative count.
-    pub fn new(n: isize) -> Self {
-        Semaphore {
-            counter: Mutex::new(n),
-            condvar: Condvar::new(),
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_sem_acquire_async() {
+        use futures::executor::block_on;
+
+        let sem = Arc::new(Semaphore::new(1));
+        block_on(sem.acquire_async());
+        assert_eq!(sem.available_permits(), 0);
+
+        let (tx, rx) = channel();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || {
+            block_on(async {
+                let _g = waiter.access_async().await;
+                tx.send(()).unwrap();
+            })
+        });
+
+        // The waiter can't complete until the resource is released.
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        sem.release();
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_sem_acquire_async_dropped() {
+        use futures::task::noop_waker;
+
+        let sem = Semaphore::new(0);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        {
+            let mut fut = Box::pin(Acquire::new(&sem));
+            assert!(fut.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(sem.lock_state().wakers.len(), 1);
         }
+        assert!(sem.lock_state().wakers.is_empty());
+
+        sem.release();
+        assert_eq!(sem.available_permits(), 1);
     }
 
-    /// Acquires the resource protected by the semaphore, blocking the current thread until the
-    /// resource is actually acquired.
-    ///
-    /// If no resources are available, the thread will be blocked waiting on the resource until one
-    /// is available.
-    pub fn acquire(&self) {
-        let mut count = self.counter.lock().unwrap();
-        while *count <= 0 {
-            count = self.condvar.wait(count).unwrap();
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_sem_permit_stream() {
+        use futures::StreamExt;
+
+        let sem = Arc::new(Semaphore::new(3));
+        let mut stream = sem.permit_stream();
+        let mut permits = Vec::new();
+        while let Some(permit) = stream.next().await {
+            permits.push(permit);
+            if permits.len() == 3 {
+                break;
+            }
         }
-        *count -= 1;
+        assert_eq!(sem.available_permits(), 0);
+
+        // Dropping the stream while it waits for a fourth resource doesn't consume it.
+        assert!(futures::poll!(stream.next()).is_pending());
+        drop(stream);
+        drop(permits);
+        assert_eq!(sem.available_permits(), 3);
+
+        // The stream ends once the semaphore is closed.
+        sem.close();
+        assert!(sem.permit_stream().next().await.is_none());
     }
 
-    /// Release a resource from the semaphore.
-    ///
-    /// Increments the semaphore's count and notifies any pending threads if necssary.
-    pub fn release(&self) {
-        let mut count = self.counter.lock().unwrap();
-        *count += 1;
-        self.condvar.notify_one();
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_sem_watch() {
+        let sem = Semaphore::new(2);
+        let mut rx = sem.watch();
+        assert_eq!(*rx.borrow_and_update(), 2);
+
+        sem.acquire();
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow_and_update(), 1);
+
+        let g = sem.access_async().await;
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow_and_update(), 0);
+
+        drop(g);
+        sem.release();
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow_and_update(), 2);
+
+        // Every receiver sees the same count.
+        assert_eq!(*sem.watch().borrow(), 2);
     }
 
-    /// Acquires a resource of this semaphore, returning an RAII guard to release the semaphore
-    /// when the guard is dropped.
-    ///
-    /// This function is semantically equivalent to an `acquire` followed by a `release` when the
-    /// returned guard is dropped.
-    pub fn access(&self) -> SemaphoreGuard {
-        self.acquire();
-        SemaphoreGuard { sem: self }
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_sem_fair_sync_async_order() {
+        use futures::task::noop_waker;
+
+        let sem = Arc::new(Semaphore::fair(0));
+        let (tx, rx) = channel();
+
+        // The blocking waiter arrives first.
+        let (sync_sem, sync_tx) = (sem.clone(), tx.clone());
+        let sync = thread::spawn(move || {
+            sync_sem.acquire();
+            sync_tx.send("sync").unwrap();
+        });
+        while sem.lock_state().queue.is_empty() {
+            thread::yield_now();
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(Acquire::new(sem.clone()));
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(sem.lock_state().queue.len(), 2);
+
+        // The first resource goes to the earlier, blocking, waiter even though the future is
+        // polled straight after the release.
+        sem.release();
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(rx.recv().unwrap(), "sync");
+        sync.join().unwrap();
+
+        sem.release();
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(sem.available_permits(), 0);
+        assert!(sem.lock_state().queue.is_empty());
     }
-}
 
-// Implement the Drop trait to specify that the SemaphoreGuard should release the semaphore when
-// the guard goes out of scope.
-impl<'a> Drop for SemaphoreGuard<'a> {
-    fn drop(&mut self) {
-        self.sem.release()
+    #[test]
+    fn test_sem_try_acquire_up_to_n() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.try_acquire_up_to_n(2), 2);
+        assert_eq!(sem.try_acquire_up_to_n(5), 1);
+        assert_eq!(sem.try_acquire_up_to_n(5), 0);
+
+        sem.release_n(3);
+        assert_eq!(sem.try_acquire_up_to_n(0), 0);
+        assert_eq!(sem.try_acquire_up_to_n(usize::MAX), 3);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_sem_sized_to_cpus() {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get()) as isize;
+        assert_eq!(Semaphore::sized_to_cpus().available_permits(), cpus);
+        assert_eq!(Semaphore::sized_to(2.0).available_permits(), 2 * cpus);
+        assert_eq!(
+            Semaphore::sized_to(0.5).available_permits(),
+            (cpus / 2).max(1)
+        );
+        assert_eq!(Semaphore::sized_to(1e-9).available_permits(), 1);
+    }
 
-    use std::sync::mpsc::channel;
-    use std::sync::Arc;
-    use std::thread;
+    #[test]
+    #[should_panic(expected = "fraction of CPUs must be positive")]
+    fn test_sem_sized_to_negative() {
+        Semaphore::sized_to(-1.0);
+    }
 
     #[test]
-    fn test_sem_basic() {
-        let s = Semaphore::new(1);
-        let _ = s.access();
+    fn test_sem_wait_for_available() {
+        let sem = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let monitor = sem.clone();
+        let t = thread::spawn(move || {
+            monitor.wait_for_available(2);
+            tx.send(()).unwrap();
+        });
+
+        // A waiting acquirer still gets the resource the monitor is woken for.
+        let acquirer = sem.clone();
+        let a = thread::spawn(move || acquirer.acquire());
+        thread::sleep(Duration::from_millis(20));
+        sem.release();
+        a.join().unwrap();
+
+        sem.release();
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        sem.release();
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(sem.available_permits(), 2);
     }
 
     #[test]
-    fn test_sem_acquire_release() {
-        let sem = Semaphore::new(1);
+    fn test_sem_scope() {
+        let sem = Semaphore::new(3);
+        let held = sem.scope(|scope| {
+            let _a = scope.acquire();
+            std::mem::forget(scope.acquire());
+            assert_eq!(sem.available_permits(), 1);
+            scope.held()
+        });
+        assert_eq!(held, 2);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_scope_panic() {
+        let sem = Semaphore::new(2);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            sem.scope(|scope| {
+                let _a = scope.acquire();
+                std::mem::forget(scope.try_acquire().unwrap());
+                assert!(scope.try_acquire().is_none());
+                panic!("scope body failed");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_release_n_wakes_exactly() {
+        let sem = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (sem, tx) = (sem.clone(), tx.clone());
+                thread::spawn(move || {
+                    sem.acquire();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+        while sem.waiter_count() < 8 {
+            thread::yield_now();
+        }
+
+        sem.release_n(3);
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        sem.release_n(5);
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(rx.try_iter().count(), 5);
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_release_n_mixed_waiters() {
+        // A batch waiter among single waiters must not swallow a wakeup the others needed.
+        let sem = Arc::new(Semaphore::new(0));
+        let batch = {
+            let sem = sem.clone();
+            thread::spawn(move || drop(sem.acquire_checked_n(3).unwrap()))
+        };
+        let singles: Vec<_> = (0..2)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || sem.acquire())
+            })
+            .collect();
+        while sem.waiter_count() < 3 || sem.batch_waiters.load(Ordering::Relaxed) < 1 {
+            thread::yield_now();
+        }
+
+        sem.release_n(2);
+        for h in singles {
+            h.join().unwrap();
+        }
+        sem.release_n(3);
+        batch.join().unwrap();
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_access_pulsed() {
+        let sem = Arc::new(Semaphore::new(1));
+        let pulsed = sem.access_pulsed(Duration::from_millis(10));
+
+        // The waiter is served during one of the pulses, while the guard is still held.
+        let (tx, rx) = channel();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || {
+            waiter.acquire();
+            tx.send(()).unwrap();
+            waiter.release();
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        t.join().unwrap();
+
+        // The pulser took the resource back once the waiter let go.
+        thread::sleep(Duration::from_millis(20));
+        assert!(!sem.try_acquire());
+        drop(pulsed);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_access_pulsed_drop_while_handed_off() {
+        let sem = Arc::new(Semaphore::new(1));
+        let pulsed = sem.access_pulsed(Duration::from_millis(5));
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel::<()>();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || {
+            waiter.acquire();
+            tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            waiter.release();
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The pulser is waiting for the resource back, but dropping the guard doesn't wait for
+        // the waiter to let go.
+        let started = Instant::now();
+        drop(pulsed);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        done_tx.send(()).unwrap();
+        t.join().unwrap();
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "already holds")]
+    fn test_sem_access_pulsed_holder() {
+        // The resource taken back by the pulser is still held by the guard's owner.
+        let sem = Arc::new(Semaphore::new(1));
+        let _pulsed = sem.access_pulsed(Duration::from_millis(1));
+        let waiter = sem.clone();
+        thread::spawn(move || drop(waiter.access())).join().unwrap();
+        while sem.available_permits() > 0 {
+            thread::yield_now();
+        }
+        sem.acquire();
+    }
+
+    #[test]
+    fn test_sem_try_acquire_weighted() {
+        let sem = Semaphore::with_max(5, 5);
+        assert!(sem.try_acquire_weighted(3));
+        assert!(!sem.try_acquire_weighted(3));
+        assert_eq!(sem.available_permits(), 2);
+        assert!(sem.try_acquire_weighted(2));
+
+        sem.release_n(5);
+        assert!(!sem.try_acquire_weighted(6));
+        assert!(sem.try_acquire_weighted(0));
+        assert_eq!(sem.available_permits(), 5);
+    }
+
+    #[test]
+    fn test_sem_display() {
+        let sem = Arc::new(Semaphore::with_max(1, 5));
+        let _g = sem.access();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire());
+        while sem.waiter_count() < 1 {
+            thread::yield_now();
+        }
+        assert_eq!(
+            sem.to_string(),
+            "Semaphore { available: 0, waiters: 1, capacity: Some(5) }"
+        );
+
+        let state = sem.lock_state();
+        assert_eq!(sem.to_string(), "Semaphore { <locked> }");
+        drop(state);
+        sem.release();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_sem_waiter_count() {
+        const THREADS: usize = 8;
+        let sem = Arc::new(Semaphore::new(2));
+        let done = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let sem = sem.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        if i % 2 == 0 {
+                            sem.acquire();
+                            sem.release();
+                        } else {
+                            drop(sem.acquire_checked_n(2).unwrap());
+                        }
+                    }
+                })
+            })
+            .collect();
+        let sampler = {
+            let (sem, done) = (sem.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    assert!(sem.waiter_count() < THREADS);
+                }
+            })
+        };
+        for h in handles {
+            h.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        sampler.join().unwrap();
+        assert_eq!(sem.waiter_count(), 0);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_sem_max_wait() {
+        let sem = Arc::new(Semaphore::new(0));
+        sem.release();
+        sem.acquire();
+        assert!(sem.max_wait() < Duration::from_millis(50));
+
+        let releaser = sem.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            releaser.release();
+        });
+        sem.acquire();
+        t.join().unwrap();
+        assert!(sem.max_wait() >= Duration::from_millis(50));
+
+        // A shorter wait leaves the high-water mark alone.
+        let max = sem.max_wait();
+        sem.release();
+        sem.acquire();
+        assert_eq!(sem.max_wait(), max);
+    }
+
+    #[test]
+    fn test_sem_named() {
+        let a = Semaphore::named("test_sem_named", 8);
+        let b = thread::spawn(|| Semaphore::named("test_sem_named", 2))
+            .join()
+            .unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(b.available_permits(), 8);
+
+        a.acquire();
+        assert_eq!(b.available_permits(), 7);
+        assert!(!Arc::ptr_eq(
+            &a,
+            &Semaphore::named("test_sem_named_other", 8)
+        ));
+    }
+
+    #[test]
+    fn test_sem_acquire_parkable() {
+        let sem = Arc::new(Semaphore::new(0));
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire_parkable());
+        while sem.lock_state().parked.is_empty() {
+            thread::yield_now();
+        }
+        assert_eq!(sem.waiter_count(), 1);
+        t.thread().unpark();
+        assert!(!t.join().unwrap());
+        assert!(sem.lock_state().parked.is_empty());
+        assert_eq!(sem.waiter_count(), 0);
+
+        // Nothing was taken, so the next release goes to the next parked thread.
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire_parkable());
+        while sem.lock_state().parked.is_empty() {
+            thread::yield_now();
+        }
+        sem.release();
+        assert!(t.join().unwrap());
+        assert_eq!(sem.available_permits(), 0);
+
+        // It fails straight away if no more threads may wait.
+        assert!(!Semaphore::with_max_waiters(0, 0).acquire_parkable());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_sem_sync_point() {
+        const THREADS: usize = 8;
+        let sem = Arc::new(Semaphore::builder(0).coalesce_releases(true).build());
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (sem, tx) = (sem.clone(), tx.clone());
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        sem.release();
+                    }
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        // Once one thread's releases have returned, a concurrent coalescing release may still be
+        // holding some of them, so only a synchronized reading is sure to include them all.
+        rx.recv().unwrap();
+        assert!(sem.sync_point() >= 100);
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.sync_point(), 100 * THREADS as isize);
+    }
+
+    #[test]
+    fn test_sem_prewarm() {
+        const WAITERS: usize = 16;
+        let sem = Arc::new(Semaphore::fair(0));
+        sem.prewarm(WAITERS);
+        let capacity = sem.lock_state().queue.capacity();
+        assert!(capacity >= WAITERS);
+
+        let handles: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let sem = sem.clone();
+                thread::spawn(move || sem.acquire())
+            })
+            .collect();
+        while sem.lock_state().queue.len() < WAITERS {
+            thread::yield_now();
+        }
+        assert_eq!(sem.lock_state().queue.capacity(), capacity);
+
+        sem.release_n(WAITERS as isize);
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sem_merge() {
+        let sem = Semaphore::with_max(2, 2);
+        sem.merge(Semaphore::new(3));
+        assert_eq!(sem.available_permits(), 5);
+        assert_eq!(sem.capacity(), Some(5));
+
+        // Merged resources go to the threads already waiting.
+        let sem = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let (sem, tx) = (sem.clone(), tx.clone());
+                thread::spawn(move || {
+                    sem.acquire();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+        while sem.waiter_count() < 3 {
+            thread::yield_now();
+        }
+        let other = Semaphore::new(3);
+        other.acquire();
+        sem.merge(other);
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+
+        sem.release();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_in_scope() {
+        let sem = Arc::new(Semaphore::new(0));
+        let parent = CancelScope::new();
+        let child = parent.child();
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire_in_scope(&child));
+        while sem.lock_state().parked.is_empty() {
+            thread::yield_now();
+        }
+        parent.cancel();
+        assert!(!t.join().unwrap());
+
+        // The cancelled acquisition took nothing, and an uncancelled scope acquires as usual.
+        sem.release();
+        assert!(!sem.acquire_in_scope(&parent));
+        assert!(sem.acquire_in_scope(&CancelScope::new()));
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_sem_acquire_up_to() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.acquire_up_to(5), 3);
+        assert_eq!(sem.available_permits(), 0);
+        assert_eq!(sem.acquire_up_to(5), 0);
+
+        sem.release_n(3);
+        assert_eq!(sem.acquire_up_to(2), 2);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_sem_access_up_to() {
+        let sem = Semaphore::new(3);
+        let guard = sem.access_up_to(5);
+        assert_eq!(sem.available_permits(), 0);
+        let empty = sem.access_up_to(5);
+        drop(empty);
+        assert_eq!(sem.available_permits(), 0);
+        drop(guard);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_sem_into_inner() {
+        let sem = Semaphore::new(3);
+        sem.acquire();
         sem.acquire();
         sem.release();
+        assert_eq!(sem.into_inner(), 2);
+
+        // A panic while the lock is held poisons it, but the count is still recovered.
+        let sem = Arc::new(Semaphore::new(1));
+        let sem2 = sem.clone();
+        let _ = thread::spawn(move || {
+            let _state = sem2.lock_state();
+            panic!("poisoning the lock");
+        })
+        .join();
+        let sem = Arc::try_unwrap(sem).ok().unwrap();
+        assert_eq!(sem.into_inner(), 1);
+    }
+
+    #[test]
+    #[cfg(not(loom))]
+    fn test_sem_clear_poison() {
+        let sem = Arc::new(Semaphore::new(1));
+        assert!(!sem.is_poisoned());
+        let sem2 = sem.clone();
+        let _ = thread::spawn(move || {
+            let _state = sem2.lock_state();
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(sem.is_poisoned());
+        let sem2 = sem.clone();
+        assert!(thread::spawn(move || sem2.acquire()).join().is_err());
+
+        sem.clear_poison();
+        assert!(!sem.is_poisoned());
         sem.acquire();
+        assert!(!sem.try_acquire());
+        sem.release();
+        assert_eq!(sem.available_permits(), 1);
     }
 
     #[test]
@@ -107,6 +6300,40 @@ mod tests {
         let _g = s.access();
     }
 
+    #[test]
+    fn test_sem_as_mutex_scoped() {
+        let s = Semaphore::new(1);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _g = s.access();
+            });
+
+            let _g = s.access();
+        });
+
+        assert!(s.try_acquire());
+    }
+
+    #[test]
+    fn test_sem_scoped_guards() {
+        let s = Semaphore::new(2);
+        let in_use = AtomicIsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let _g = s.access();
+                    assert!(in_use.fetch_add(1, Ordering::SeqCst) < 2);
+                    thread::sleep(Duration::from_millis(5));
+                    in_use.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(s.lock_state().count, 2);
+    }
+
     #[test]
     fn test_signaling_3_1() {
         let parent = Arc::new(Semaphore::new(0));
@@ -202,3 +6429,72 @@ mod tests {
         rx.recv().unwrap();
     }
 }
+
+// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`. The other tests use the
+// standard library's threads, which loom's primitives can't be used from.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn loom_count_conserved() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(1));
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let sem = sem.clone();
+                    thread::spawn(move || drop(sem.access()))
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+            assert_eq!(sem.available_permits(), 1);
+        });
+    }
+
+    #[test]
+    fn loom_release_wakes_waiter() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(0));
+            let waiter = sem.clone();
+            let t = thread::spawn(move || waiter.acquire());
+            sem.release();
+            t.join().unwrap();
+            assert_eq!(sem.available_permits(), 0);
+        });
+    }
+
+    #[test]
+    fn loom_release_n_wakes_each_waiter() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(0));
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let sem = sem.clone();
+                    thread::spawn(move || sem.acquire())
+                })
+                .collect();
+            sem.release_n(2);
+            for h in handles {
+                h.join().unwrap();
+            }
+            assert_eq!(sem.available_permits(), 0);
+        });
+    }
+
+    #[test]
+    fn loom_fast_release_wakes_waiter() {
+        loom::model(|| {
+            let sem = Arc::new(Semaphore::new(0));
+            let waiter = sem.clone();
+            let t = thread::spawn(move || waiter.acquire());
+            sem.fast_release();
+            t.join().unwrap();
+            assert_eq!(sem.available_permits(), 0);
+        });
+    }
+}
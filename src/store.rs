@@ -0,0 +1,123 @@
+/// A backend holding the count of a semaphore, such as a key in an external store shared between
+/// processes.
+///
+/// A semaphore created with `Semaphore::with_store` takes resources from the store and gives them
+/// back to it, only using its own lock and condvar to wait for releases. The store is never called
+/// with the semaphore's state locked, so it may block on the network.
+pub trait PermitStore: Send + Sync {
+    /// Takes `n` resources if they are all available, returning whether they were taken.
+    fn try_take(&self, n: isize) -> bool;
+
+    /// Returns `n` resources.
+    fn give(&self, n: isize);
+}
+
+/// The store of a semaphore which keeps its count in memory, which is the default.
+///
+/// The count is kept by the semaphore itself, so this store always has resources to take and
+/// ignores those given back.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InMemory;
+
+impl PermitStore for InMemory {
+    #[inline]
+    fn try_take(&self, _n: isize) -> bool {
+        true
+    }
+
+    #[inline]
+    fn give(&self, _n: isize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::semaphore::Semaphore;
+
+    /// A store which keeps its count in an atomic, standing in for an external one.
+    struct MockStore {
+        count: AtomicIsize,
+        calls: AtomicUsize,
+    }
+
+    impl PermitStore for MockStore {
+        fn try_take(&self, n: isize) -> bool {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut count = self.count.load(Ordering::SeqCst);
+            while count >= n {
+                match self.count.compare_exchange(
+                    count,
+                    count - n,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => return true,
+                    Err(actual) => count = actual,
+                }
+            }
+            false
+        }
+
+        fn give(&self, n: isize) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.count.fetch_add(n, Ordering::SeqCst);
+        }
+    }
+
+    fn mock(n: isize) -> MockStore {
+        MockStore {
+            count: AtomicIsize::new(n),
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn test_store_semaphore() {
+        let sem = Arc::new(Semaphore::with_store(mock(1), Duration::from_secs(10)));
+        let guard = sem.access();
+        assert!(!sem.try_acquire());
+        assert_eq!(sem.store().count.load(Ordering::SeqCst), 0);
+
+        // The release through the semaphore wakes the waiter well before it would poll.
+        let waiter = sem.clone();
+        let t = thread::spawn(move || drop(waiter.access()));
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        t.join().unwrap();
+
+        assert_eq!(sem.store().count.load(Ordering::SeqCst), 1);
+        assert!(sem.store().calls.load(Ordering::SeqCst) >= 5);
+    }
+
+    #[test]
+    fn test_store_semaphore_batch() {
+        let sem = Semaphore::with_store(mock(3), Duration::from_secs(10));
+        let guard = sem.acquire_checked_n(2).unwrap();
+        sem.acquire();
+        assert!(sem.try_access().is_none());
+        drop(guard);
+        sem.release();
+        assert_eq!(sem.store().count.load(Ordering::SeqCst), 3);
+
+        let owned = Arc::new(sem).access_owned();
+        thread::spawn(move || drop(owned)).join().unwrap();
+    }
+
+    #[test]
+    fn test_store_semaphore_polls() {
+        let sem = Arc::new(Semaphore::with_store(mock(0), Duration::from_millis(1)));
+
+        // A release made directly to the store is picked up by polling.
+        let waiter = sem.clone();
+        let t = thread::spawn(move || waiter.acquire());
+        thread::sleep(Duration::from_millis(10));
+        sem.store().give(1);
+        t.join().unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! The synchronization primitives semaphores are built on.
+//!
+//! Building with `RUSTFLAGS="--cfg loom"` swaps these for the primitives of the `loom` model
+//! checker, so that loom tests can explore every interleaving of the operations on them. Normal
+//! builds use the standard library's.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(all(loom, debug_assertions))]
+pub(crate) use loom::thread::ThreadId;
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(all(not(loom), debug_assertions))]
+pub(crate) use std::thread::ThreadId;
+
+pub(crate) mod atomic {
+    #[cfg(loom)]
+    pub(crate) use loom::sync::atomic::{
+        AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering,
+    };
+    #[cfg(not(loom))]
+    pub(crate) use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+}
+
+/// Returns the id of the current thread, as known to the model checker under loom.
+#[cfg(all(loom, debug_assertions))]
+pub(crate) fn current_thread_id() -> ThreadId {
+    loom::thread::current().id()
+}
+
+/// Returns the id of the current thread.
+#[cfg(all(not(loom), debug_assertions))]
+pub(crate) fn current_thread_id() -> ThreadId {
+    std::thread::current().id()
+}
+
+/// Orders a preceding `SeqCst` store before a following `SeqCst` load of another atomic, as
+/// `fast_release` and waiting threads rely on to always see each other.
+///
+/// `SeqCst` accesses already guarantee this, so this does nothing in normal builds. Loom models
+/// them as `AcqRel`, which would report lost wakeups that can't happen, so under loom this is a
+/// real fence.
+#[inline]
+pub(crate) fn store_load_fence() {
+    #[cfg(loom)]
+    loom::sync::atomic::fence(atomic::Ordering::SeqCst);
+}
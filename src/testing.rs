@@ -0,0 +1,134 @@
+//! Helpers for testing the fairness of primitives built on semaphores.
+//!
+//! `spawn_waiters` starts threads which block on a semaphore one after another in a known order,
+//! and records the order in which they are served; `assert_served_in_order` then checks that it
+//! matches their arrival order.
+
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::semaphore::SemaphoreLike;
+
+/// How long each waiter is given to block on the semaphore before the next one is spawned.
+const STAGGER: Duration = Duration::from_millis(20);
+
+/// A group of threads waiting on a semaphore, created by `spawn_waiters`.
+pub struct Waiters {
+    /// The waiting threads, in arrival order.
+    handles: Vec<JoinHandle<()>>,
+    /// The arrival indices of the waiters, in the order they were served.
+    served: Arc<Mutex<Vec<usize>>>,
+}
+
+/// Spawns `n` threads which each acquire a resource from `sem`, record that they were served and
+/// release it again.
+///
+/// The threads are spawned one at a time, giving each one time to block on the semaphore before
+/// the next arrives, so their arrival order is their index. Releasing a single resource lets the
+/// whole group through in the order the semaphore serves them.
+pub fn spawn_waiters<S>(sem: &Arc<S>, n: usize) -> Waiters
+where
+    S: SemaphoreLike + Send + Sync + 'static,
+{
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let handles = (0..n)
+        .map(|i| {
+            let (sem, served) = (sem.clone(), served.clone());
+            let handle = thread::spawn(move || {
+                sem.acquire();
+                served.lock().unwrap().push(i);
+                sem.release();
+            });
+            thread::sleep(STAGGER);
+            handle
+        })
+        .collect();
+    Waiters { handles, served }
+}
+
+impl Waiters {
+    /// Waits for every waiter to be served, returning their arrival indices in the order they
+    /// were served.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a waiter panicked.
+    pub fn join(self) -> Vec<usize> {
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+        let served = self.served.lock().unwrap();
+        served.clone()
+    }
+}
+
+/// Asserts that waiters were served in the order they arrived, given their arrival indices in the
+/// order they were served.
+///
+/// # Panics
+///
+/// Panics, describing the first waiter served out of turn, if the order is not ascending.
+pub fn assert_served_in_order(served: &[usize]) {
+    if let Some(w) = served.windows(2).find(|w| w[0] > w[1]) {
+        panic!(
+            "waiter {} was served before waiter {}, which arrived earlier (served order: {:?})",
+            w[0], w[1], served
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Condvar;
+
+    use crate::semaphore::Semaphore;
+
+    /// A deliberately unfair semaphore which serves the most recent arrival first.
+    struct LifoSemaphore {
+        /// The count and the stack of waiting tickets.
+        state: Mutex<(isize, Vec<u64>, u64)>,
+        condvar: Condvar,
+    }
+
+    impl SemaphoreLike for LifoSemaphore {
+        fn acquire(&self) {
+            let mut state = self.state.lock().unwrap();
+            let ticket = state.2;
+            state.2 += 1;
+            state.1.push(ticket);
+            while !(state.0 > 0 && state.1.last() == Some(&ticket)) {
+                state = self.condvar.wait(state).unwrap();
+            }
+            state.1.pop();
+            state.0 -= 1;
+        }
+
+        fn release(&self) {
+            self.state.lock().unwrap().0 += 1;
+            self.condvar.notify_all();
+        }
+    }
+
+    #[test]
+    fn test_fair_semaphore_served_in_order() {
+        let sem = Arc::new(Semaphore::fair(0));
+        let waiters = spawn_waiters(&sem, 4);
+        sem.release();
+        assert_served_in_order(&waiters.join());
+    }
+
+    #[test]
+    #[should_panic(expected = "was served before waiter")]
+    fn test_unfair_semaphore_detected() {
+        let sem = Arc::new(LifoSemaphore {
+            state: Mutex::new((0, Vec::new(), 0)),
+            condvar: Condvar::new(),
+        });
+        let waiters = spawn_waiters(&sem, 3);
+        sem.release();
+        assert_served_in_order(&waiters.join());
+    }
+}
@@ -0,0 +1,306 @@
+use std::time::Instant;
+
+#[cfg(feature = "numa")]
+use crate::locality::{self, Location};
+
+/// The most times in a row a waiter near the releasing thread may be served ahead of the waiter
+/// which would otherwise be next, so that preferring local waiters can't starve remote ones.
+#[cfg(feature = "numa")]
+const MAX_LOCAL_BYPASSES: u32 = 4;
+
+/// A thread that is queued waiting for a resource.
+struct Waiter {
+    /// The ticket handed out when the waiter was enqueued. Tickets increase monotonically, so
+    /// they double as arrival order.
+    ticket: u64,
+    /// The priority requested by the waiter. Higher priorities are served first.
+    priority: u32,
+    /// When the waiter was enqueued, used to age its priority.
+    enqueued: Instant,
+    /// Where the waiter was running when it was enqueued, if the queue prefers local waiters.
+    #[cfg(feature = "numa")]
+    location: Option<Location>,
+}
+
+/// The queue of threads waiting on a semaphore, used by fair and priority acquisitions to decide
+/// which waiter is served next.
+///
+/// Waiters are served highest effective priority first, with ties broken by arrival order, or by
+/// reverse arrival order for a LIFO queue. A waiter's effective priority is its requested priority
+/// plus `aging_rate` levels for every second it has spent waiting, so low priority waiters are
+/// eventually served even under a steady stream of high priority arrivals.
+///
+/// With the `numa` feature, the queue can instead prefer, among the waiters ranked equally with
+/// the next one, a waiter on the same CPU as the last releasing thread, or failing that on the
+/// same NUMA node, whose caches are likelier to hold what the releaser touched.
+pub(crate) struct WaitQueue {
+    waiters: Vec<Waiter>,
+    next_ticket: u64,
+    /// Priority levels gained per second of waiting.
+    aging_rate: f64,
+    /// Whether ties are broken in favor of the latest arrival rather than the earliest.
+    lifo: bool,
+    /// Whether waiters near the last releasing thread are preferred.
+    #[cfg(feature = "numa")]
+    locality: bool,
+    /// Where the last release was made, while the queue prefers local waiters.
+    #[cfg(feature = "numa")]
+    released_at: Option<Location>,
+    /// How many times in a row a local waiter has been served ahead of the waiter which would
+    /// otherwise have been next.
+    #[cfg(feature = "numa")]
+    bypassed: u32,
+}
+
+impl WaitQueue {
+    pub(crate) fn new(aging_rate: f64) -> Self {
+        WaitQueue {
+            waiters: Vec::new(),
+            next_ticket: 0,
+            aging_rate,
+            lifo: false,
+            #[cfg(feature = "numa")]
+            locality: false,
+            #[cfg(feature = "numa")]
+            released_at: None,
+            #[cfg(feature = "numa")]
+            bypassed: 0,
+        }
+    }
+
+    /// Sets whether waiters near the last releasing thread are preferred over equally ranked
+    /// waiters further away.
+    #[cfg(feature = "numa")]
+    pub(crate) fn set_locality(&mut self, locality: bool) {
+        self.locality = locality;
+    }
+
+    /// Records that the current thread released a resource, if the queue prefers waiters near
+    /// the releaser.
+    #[cfg(feature = "numa")]
+    pub(crate) fn released_here(&mut self) {
+        if self.locality {
+            self.released_at = Some(locality::current());
+        }
+    }
+
+    /// Sets whether ties between waiters are broken in favor of the latest arrival.
+    pub(crate) fn set_lifo(&mut self, lifo: bool) {
+        self.lifo = lifo;
+    }
+
+    /// Makes room for at least `waiters` queued waiters, so that queueing that many never
+    /// allocates.
+    pub(crate) fn reserve(&mut self, waiters: usize) {
+        self.waiters
+            .reserve(waiters.saturating_sub(self.waiters.len()));
+    }
+
+    /// Adds a waiter with the given priority to the queue, returning its ticket.
+    pub(crate) fn enqueue(&mut self, priority: u32) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push(Waiter {
+            ticket,
+            priority,
+            enqueued: Instant::now(),
+            #[cfg(feature = "numa")]
+            location: if self.locality {
+                Some(locality::current())
+            } else {
+                None
+            },
+        });
+        ticket
+    }
+
+    /// Removes the waiter holding `ticket` from the queue.
+    pub(crate) fn remove(&mut self, ticket: u64) {
+        #[cfg(feature = "numa")]
+        if self.locality {
+            self.count_bypass(ticket);
+        }
+        if let Some(i) = self.waiters.iter().position(|w| w.ticket == ticket) {
+            self.waiters.remove(i);
+        }
+    }
+
+    /// Returns the ticket of the waiter that should be served next.
+    #[cfg_attr(not(feature = "numa"), allow(unused_variables))]
+    pub(crate) fn head(&self) -> Option<u64> {
+        let now = Instant::now();
+        let (best, best_p) = self.ranked_head(now)?;
+        #[cfg(feature = "numa")]
+        if let Some(local) = self.local_head(best, best_p, now) {
+            return Some(local);
+        }
+        Some(best.ticket)
+    }
+
+    /// Returns the waiter ranked first by effective priority and arrival order, along with its
+    /// effective priority.
+    fn ranked_head(&self, now: Instant) -> Option<(&Waiter, f64)> {
+        let mut best: Option<(&Waiter, f64)> = None;
+        for w in &self.waiters {
+            let p = self.effective(w, now);
+            match best {
+                // Waiters are stored in arrival order, so only a strictly higher effective
+                // priority displaces an earlier waiter, unless later arrivals win ties.
+                Some((_, best_p)) if p < best_p || (p == best_p && !self.lifo) => {}
+                _ => best = Some((w, p)),
+            }
+        }
+        best
+    }
+
+    /// Returns the priority of a waiter after aging it for the time it has waited until `now`.
+    fn effective(&self, w: &Waiter, now: Instant) -> f64 {
+        let waited = now.duration_since(w.enqueued).as_secs_f64();
+        f64::from(w.priority) + self.aging_rate * waited
+    }
+
+    /// Returns the ticket of a waiter ranked equally with `best` but closer to the last release,
+    /// if the queue prefers local waiters and hasn't bypassed `best`'s place too often already.
+    #[cfg(feature = "numa")]
+    fn local_head(&self, best: &Waiter, best_p: f64, now: Instant) -> Option<u64> {
+        if !self.locality || self.bypassed >= MAX_LOCAL_BYPASSES {
+            return None;
+        }
+        let released = self.released_at?;
+        let distance = |w: &Waiter| match w.location {
+            Some(at) if at.cpu == released.cpu => 0,
+            Some(at) if at.node == released.node => 1,
+            _ => 2,
+        };
+
+        // Equally ranked waiters are taken in the order ties are broken in, so the earliest of
+        // the closest waiters is chosen, or the latest for a LIFO queue.
+        let tied = self
+            .waiters
+            .iter()
+            .filter(|w| self.effective(w, now) == best_p);
+        let closest = if self.lifo {
+            tied.rev().min_by_key(|w| distance(w))
+        } else {
+            tied.min_by_key(|w| distance(w))
+        }?;
+        if distance(closest) < distance(best) {
+            Some(closest.ticket)
+        } else {
+            None
+        }
+    }
+
+    /// Counts how many times in a row the waiter which would otherwise have been next has been
+    /// bypassed, given the waiter holding `ticket` is about to leave the queue.
+    #[cfg(feature = "numa")]
+    fn count_bypass(&mut self, ticket: u64) {
+        let ranked = match self.ranked_head(Instant::now()) {
+            Some((w, _)) => w.ticket,
+            None => return,
+        };
+        if ranked == ticket {
+            self.bypassed = 0;
+        } else if self.head() == Some(ticket) {
+            self.bypassed += 1;
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.waiters.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_priority_then_arrival() {
+        let mut q = WaitQueue::new(0.0);
+        let a = q.enqueue(1);
+        let b = q.enqueue(5);
+        let c = q.enqueue(5);
+
+        assert_eq!(q.head(), Some(b));
+        q.remove(b);
+        assert_eq!(q.head(), Some(c));
+        q.remove(c);
+        assert_eq!(q.head(), Some(a));
+        q.remove(a);
+        assert_eq!(q.head(), None);
+    }
+
+    #[test]
+    fn test_queue_lifo() {
+        let mut q = WaitQueue::new(0.0);
+        q.set_lifo(true);
+        let a = q.enqueue(0);
+        let b = q.enqueue(0);
+        let c = q.enqueue(1);
+
+        assert_eq!(q.head(), Some(c));
+        q.remove(c);
+        assert_eq!(q.head(), Some(b));
+        q.remove(b);
+        assert_eq!(q.head(), Some(a));
+    }
+
+    #[test]
+    fn test_queue_aging() {
+        let mut q = WaitQueue::new(100.0);
+        let low = q.enqueue(0);
+        thread::sleep(Duration::from_millis(100));
+        let _high = q.enqueue(5);
+
+        assert_eq!(q.head(), Some(low));
+    }
+
+    #[cfg(feature = "numa")]
+    #[test]
+    fn test_queue_locality() {
+        let at = |cpu, node| Some(Location { cpu, node });
+        let mut q = WaitQueue::new(0.0);
+        q.set_locality(true);
+        // The queue places waiters where they enqueue from, so they are moved by hand.
+        let remote = q.enqueue(0);
+        let locals: Vec<_> = (0..5).map(|_| q.enqueue(0)).collect();
+        q.waiters[0].location = at(8, 1);
+        for (i, w) in q.waiters[1..].iter_mut().enumerate() {
+            w.location = at(i, 0);
+        }
+
+        // Nothing has been released yet, so arrival order decides.
+        assert_eq!(q.head(), Some(remote));
+        q.released_at = at(3, 0);
+        assert_eq!(q.head(), Some(locals[3]));
+        q.released_at = at(7, 0);
+        assert_eq!(q.head(), Some(locals[0]));
+        q.released_at = at(9, 1);
+        assert_eq!(q.head(), Some(remote));
+
+        // The remote waiter is only bypassed a few times in a row.
+        q.released_at = at(7, 0);
+        let mut served = Vec::new();
+        while let Some(ticket) = q.head() {
+            q.remove(ticket);
+            served.push(ticket);
+        }
+        let mut expected = locals[..4].to_vec();
+        expected.extend([remote, locals[4]]);
+        assert_eq!(served, expected);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::semaphore::Semaphore;
+
+/// A semaphore which reclaims any resource held for longer than a maximum hold time.
+///
+/// A background reaper force-releases resources whose holders have kept them past the limit, for
+/// example because the holder hung, so a stuck holder can't leak a resource for good. There is no
+/// way to extend a hold. Once a resource has been reclaimed, dropping its guard does nothing, so it
+/// is never released twice.
+pub struct WatchdogSemaphore {
+    /// The semaphore whose resources are handed out.
+    sem: Semaphore,
+    /// The time each outstanding hold started, keyed by the id of its guard.
+    holds: Mutex<HashMap<u64, Instant>>,
+    /// The id of the next guard.
+    next_id: AtomicU64,
+    /// How long a resource may be held before it is reclaimed.
+    max_hold: Duration,
+}
+
+/// An RAII guard which will release a resource acquired from a `WatchdogSemaphore` when dropped,
+/// unless the reaper has already reclaimed it.
+pub struct WatchdogGuard<'a> {
+    /// The semaphore being guarded.
+    sem: &'a WatchdogSemaphore,
+    /// The id of this hold.
+    id: u64,
+}
+
+impl WatchdogSemaphore {
+    /// Creates a semaphore with `initial` resources, none of which may be held for longer than
+    /// `max_hold`, and starts its reaper.
+    ///
+    /// The reaper runs on its own thread for as long as the semaphore is alive, and checks for
+    /// overdue holds whenever the oldest one is due, so a resource is reclaimed shortly after it
+    /// has been held for `max_hold`.
+    pub fn with_max_hold(initial: isize, max_hold: Duration) -> Arc<Self> {
+        let sem = Arc::new(WatchdogSemaphore {
+            // The reaper releases resources on behalf of the threads holding them.
+            sem: Semaphore::new(initial).for_signalling(),
+            holds: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            max_hold,
+        });
+        let reaper = Arc::downgrade(&sem);
+        thread::spawn(move || reap_until_dropped(reaper));
+        sem
+    }
+
+    /// Returns the longest a resource may be held before it is reclaimed.
+    pub fn max_hold(&self) -> Duration {
+        self.max_hold
+    }
+
+    /// Acquires a resource, blocking until one is available, and returns a guard releasing it.
+    pub fn access(&self) -> WatchdogGuard<'_> {
+        self.sem.acquire();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.holds.lock().unwrap().insert(id, Instant::now());
+        WatchdogGuard { sem: self, id }
+    }
+
+    /// Attempts to acquire a resource without waiting.
+    pub fn try_access(&self) -> Option<WatchdogGuard<'_>> {
+        if !self.sem.try_acquire() {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.holds.lock().unwrap().insert(id, Instant::now());
+        Some(WatchdogGuard { sem: self, id })
+    }
+
+    /// Returns the number of resources currently available.
+    pub fn available_permits(&self) -> isize {
+        self.sem.available_permits()
+    }
+
+    /// Reclaims every resource which has been held for longer than the maximum hold time, and
+    /// returns the number reclaimed.
+    ///
+    /// The reaper calls this on its own, so it only needs calling directly to reclaim overdue
+    /// resources straight away.
+    pub fn reap(&self) -> usize {
+        self.reap_overdue().0
+    }
+
+    /// Reclaims overdue resources, returning the number reclaimed and when the oldest remaining
+    /// hold is due, if there is one.
+    fn reap_overdue(&self) -> (usize, Option<Instant>) {
+        let now = Instant::now();
+        let mut holds = self.holds.lock().unwrap();
+        let before = holds.len();
+        holds.retain(|_, &mut started| now < started + self.max_hold);
+        let reaped = before - holds.len();
+        let next_due = holds.values().min().map(|&started| started + self.max_hold);
+        drop(holds);
+
+        self.sem.release_n(reaped as isize);
+        (reaped, next_due)
+    }
+}
+
+impl<'a> WatchdogGuard<'a> {
+    /// Returns whether the resource has been reclaimed by the reaper, in which case dropping the
+    /// guard won't release it again.
+    pub fn is_reclaimed(&self) -> bool {
+        !self.sem.holds.lock().unwrap().contains_key(&self.id)
+    }
+}
+
+// A hold is released by whichever of the guard and the reaper removes it from the map first, so
+// it is released exactly once.
+impl<'a> Drop for WatchdogGuard<'a> {
+    fn drop(&mut self) {
+        if self.sem.holds.lock().unwrap().remove(&self.id).is_some() {
+            self.sem.sem.release();
+        }
+    }
+}
+
+/// Reaps overdue holds until the semaphore is dropped. Only a weak reference is kept between
+/// passes, so the reaper doesn't keep the semaphore alive.
+fn reap_until_dropped(sem: Weak<WatchdogSemaphore>) {
+    while let Some(sem) = sem.upgrade() {
+        let (_, next_due) = sem.reap_overdue();
+        // A hold started after this pass is due no sooner than `max_hold` from now.
+        let wake = next_due.unwrap_or_else(|| Instant::now() + sem.max_hold);
+        drop(sem);
+        thread::sleep(wake.saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_reclaims_overheld() {
+        let sem = WatchdogSemaphore::with_max_hold(1, Duration::from_millis(50));
+        let stuck = sem.access();
+        assert!(sem.try_access().is_none());
+
+        // The reaper reclaims the resource from the stuck holder.
+        let reclaimed = sem.access();
+        assert!(stuck.is_reclaimed());
+        assert!(!reclaimed.is_reclaimed());
+
+        // Dropping the stuck guard late must not release the resource a second time.
+        drop(stuck);
+        assert_eq!(sem.available_permits(), 0);
+        drop(reclaimed);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_watchdog_reap() {
+        let sem = WatchdogSemaphore::with_max_hold(2, Duration::from_secs(60));
+        let _g = sem.access();
+        assert_eq!(sem.reap(), 0);
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
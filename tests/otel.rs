@@ -0,0 +1,56 @@
+//! Tracing of acquisitions, in a test binary of its own since it installs a global tracer
+//! provider which would otherwise record every acquisition made by the other tests.
+#![cfg(all(feature = "otel", not(loom)))]
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use opentelemetry::trace::{TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::Value;
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+use TLBoS::semaphore::Semaphore;
+
+#[test]
+fn test_sem_acquire_span() {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    opentelemetry::global::set_tracer_provider(provider.clone());
+
+    let sem = Arc::new(Semaphore::new(1));
+    let held = sem.access();
+    let waiter = sem.clone();
+    let t = thread::spawn(move || {
+        provider.tracer("test").in_span("request", |cx| {
+            let _g = waiter.access();
+            cx.span().span_context().span_id()
+        })
+    });
+    thread::sleep(Duration::from_millis(20));
+    drop(held);
+    let request = t.join().unwrap();
+
+    // The waiter's span is picked out by its parent, past the one for acquiring `held`.
+    let spans = exporter.get_finished_spans().unwrap();
+    let span = spans
+        .iter()
+        .find(|span| span.parent_span_id == request)
+        .unwrap();
+    assert_eq!(span.name, "semaphore.acquire");
+    let attribute = |key: &str| {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.clone())
+    };
+    assert_eq!(attribute("semaphore.count"), Some(Value::I64(0)));
+    match attribute("semaphore.wait_duration") {
+        Some(Value::F64(waited)) => assert!(waited >= 0.02),
+        other => panic!("unexpected wait duration {:?}", other),
+    }
+    let spanned = span.end_time.duration_since(span.start_time).unwrap();
+    assert!(spanned >= Duration::from_millis(20));
+}